@@ -0,0 +1,642 @@
+// Typed client for the mechd varlink protocol
+//
+// `MechClient` opens one connection per call, sends a `DaemonCommand` and
+// decodes the `DaemonReply`. The `mech` binary is a thin wrapper around it;
+// other Rust programs can use it to drive tabs without scraping CLI output.
+
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Value;
+use url::Url;
+
+use crate::{
+    read_message, socket_path, write_message, Backend, ControlInfo, DaemonCall, DaemonCommand,
+    DaemonError, DaemonOk, DaemonReply, HistoryInfo, Node, RequestInfo, TabInfo, WaitCondition,
+};
+
+/// Connection settings for talking to a running mechd.
+#[derive(Debug, Clone)]
+pub struct MechClient {
+    socket: PathBuf,
+    timeout: Option<Duration>,
+    backend: Option<Backend>,
+}
+
+impl Default for MechClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MechClient {
+    /// Client for the daemon at `$MECH_SOCKET_PATH` (or `/tmp/mech.sock`).
+    pub fn new() -> Self {
+        Self::with_socket(socket_path())
+    }
+
+    /// Client for the daemon listening on `socket`.
+    pub fn with_socket(socket: impl Into<PathBuf>) -> Self {
+        MechClient {
+            socket: socket.into(),
+            timeout: None,
+            backend: None,
+        }
+    }
+
+    /// How long `open`, `use_control`, `fork` and `wait` let the daemon
+    /// wait. `Duration::ZERO` returns as soon as the command is sent; unset
+    /// uses the daemon default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The backend `open` asks for; unset uses the daemon default.
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    fn timeout_ms(&self) -> Option<u64> {
+        self.timeout.map(|t| t.as_millis() as u64)
+    }
+
+    /// Send a raw command and return the reply parameters.
+    pub fn call(&self, cmd: &DaemonCommand) -> Result<DaemonOk, DaemonError> {
+        let mut stream = self.send(cmd, false)?;
+        read_reply(&mut stream).map(|(parameters, _)| parameters)
+    }
+
+    fn send(&self, cmd: &DaemonCommand, more: bool) -> Result<UnixStream, DaemonError> {
+        let mut stream =
+            UnixStream::connect(&self.socket).map_err(|e| DaemonError::ConnectionFailed {
+                reason: e.to_string(),
+            })?;
+        let call = DaemonCall {
+            command: cmd.clone(),
+            more,
+        };
+        let json = serde_json::to_vec(&call).map_err(|e| DaemonError::InvalidReply {
+            reason: format!("failed to serialize command: {}", e),
+        })?;
+        write_message(&mut stream, &json).map_err(|e| DaemonError::ConnectionFailed {
+            reason: e.to_string(),
+        })?;
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+        Ok(stream)
+    }
+
+    /// Open `url` in a new tab and wait for its hypermap.
+    pub fn open(&self, url: &str, name: Option<&str>) -> Result<TabInfo, DaemonError> {
+        let reply = self.call(&DaemonCommand::Open {
+            url: url.to_string(),
+            name: name.map(str::to_string),
+            backend: self.backend,
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_tab(reply)
+    }
+
+    /// Fetch the hypermap of a tab, optionally narrowed to `path`.
+    pub fn show(&self, tab: &str, path: Option<&str>) -> Result<Node, DaemonError> {
+        let reply = self.call(&DaemonCommand::Show {
+            tab: tab.to_string(),
+            path: path.map(str::to_string),
+        })?;
+        expect_hypermap(reply)
+    }
+
+    /// Every node of a tab that `selector` matches, with its path.
+    pub fn select(&self, tab: &str, selector: &str) -> Result<Vec<(String, Node)>, DaemonError> {
+        let reply = self.call(&DaemonCommand::Select {
+            tab: tab.to_string(),
+            selector: selector.to_string(),
+        })?;
+        let matches = reply.matches.ok_or_else(|| DaemonError::InvalidReply {
+            reason: "reply is missing the matches".to_string(),
+        })?;
+        matches
+            .into_iter()
+            .map(|m| {
+                let node = Node::from_value(m.hypermap).map_err(|e| DaemonError::InvalidReply {
+                    reason: e.to_string(),
+                })?;
+                Ok((m.path, node))
+            })
+            .collect()
+    }
+
+    /// Like `show`, but also returns the URL of the page the node is on,
+    /// which its `href`s and `scripts` resolve against.
+    pub fn inspect(&self, tab: &str, path: Option<&str>) -> Result<(Node, Url), DaemonError> {
+        let mut reply = self.call(&DaemonCommand::Show {
+            tab: tab.to_string(),
+            path: path.map(str::to_string),
+        })?;
+        let url = reply.url.take().ok_or_else(|| DaemonError::InvalidReply {
+            reason: "reply is missing the page URL".to_string(),
+        })?;
+        let url = Url::parse(&url).map_err(|e| DaemonError::InvalidReply {
+            reason: format!("invalid page URL '{}': {}", url, e),
+        })?;
+        Ok((expect_hypermap(reply)?, url))
+    }
+
+    /// Set a value without triggering any control. `value` should be a
+    /// string, number, boolean or null.
+    pub fn set(&self, tab: &str, path: &str, value: impl Into<Value>) -> Result<(), DaemonError> {
+        self.call(&DaemonCommand::Set {
+            tab: tab.to_string(),
+            path: path.to_string(),
+            value: value.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Fill in `data` below the control at `path`, then activate it.
+    ///
+    /// Returns the URL the tab is at once any resulting load has finished.
+    pub fn use_control(
+        &self,
+        tab: &str,
+        path: &str,
+        data: &HashMap<String, Value>,
+    ) -> Result<String, DaemonError> {
+        let reply = self.call(&DaemonCommand::Use {
+            tab: tab.to_string(),
+            path: path.to_string(),
+            data: data.clone(),
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_url(reply)
+    }
+
+    /// The request `use_control` would make with the same arguments, leaving
+    /// the tab as it is.
+    pub fn preview(
+        &self,
+        tab: &str,
+        path: &str,
+        data: &HashMap<String, Value>,
+    ) -> Result<RequestInfo, DaemonError> {
+        let reply = self.call(&DaemonCommand::Preview {
+            tab: tab.to_string(),
+            path: path.to_string(),
+            data: data.clone(),
+        })?;
+        reply.request.ok_or_else(|| DaemonError::InvalidReply {
+            reason: "reply is missing the request".to_string(),
+        })
+    }
+
+    /// Block until the value at `path` satisfies `condition`, returning it.
+    ///
+    /// Fails with `DaemonError::Timeout` if the condition does not hold in
+    /// time.
+    pub fn wait(
+        &self,
+        tab: &str,
+        path: &str,
+        condition: WaitCondition,
+    ) -> Result<Node, DaemonError> {
+        let reply = self.call(&DaemonCommand::Wait {
+            tab: tab.to_string(),
+            path: path.to_string(),
+            condition,
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_hypermap(reply)
+    }
+
+    /// Stream the value at `path` (or the whole hypermap) each time it
+    /// changes, starting with its current value.
+    pub fn watch(&self, tab: &str, path: Option<&str>) -> Result<Watch, DaemonError> {
+        let stream = self.send(
+            &DaemonCommand::Watch {
+                tab: tab.to_string(),
+                path: path.map(str::to_string),
+            },
+            true,
+        )?;
+        Ok(Watch {
+            stream,
+            done: false,
+        })
+    }
+
+    /// Copy a tab, with its history and inputs, into a new one.
+    pub fn fork(&self, tab: &str, name: Option<&str>) -> Result<TabInfo, DaemonError> {
+        let reply = self.call(&DaemonCommand::Fork {
+            tab: tab.to_string(),
+            name: name.map(str::to_string),
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_tab(reply)
+    }
+
+    pub fn close(&self, tab: &str) -> Result<(), DaemonError> {
+        self.call(&DaemonCommand::Close {
+            tab: tab.to_string(),
+        })?;
+        Ok(())
+    }
+
+    pub fn name(&self, tab: &str, name: &str) -> Result<(), DaemonError> {
+        self.call(&DaemonCommand::Name {
+            tab: tab.to_string(),
+            name: name.to_string(),
+        })?;
+        Ok(())
+    }
+
+    /// Load `url` (absolute, or relative to the tab's current URL) into an
+    /// existing tab, returning the URL it ends up at.
+    pub fn goto(&self, tab: &str, url: &str) -> Result<String, DaemonError> {
+        let reply = self.call(&DaemonCommand::Goto {
+            tab: tab.to_string(),
+            url: url.to_string(),
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_url(reply)
+    }
+
+    /// Go back one page, returning the URL the tab ends up at.
+    pub fn back(&self, tab: &str) -> Result<String, DaemonError> {
+        let reply = self.call(&DaemonCommand::Back {
+            tab: tab.to_string(),
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_url(reply)
+    }
+
+    /// Go forward one page, returning the URL the tab ends up at.
+    pub fn forward(&self, tab: &str) -> Result<String, DaemonError> {
+        let reply = self.call(&DaemonCommand::Forward {
+            tab: tab.to_string(),
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_url(reply)
+    }
+
+    /// Load the current page again, returning its URL.
+    pub fn reload(&self, tab: &str) -> Result<String, DaemonError> {
+        let reply = self.call(&DaemonCommand::Reload {
+            tab: tab.to_string(),
+            timeout_ms: self.timeout_ms(),
+        })?;
+        expect_url(reply)
+    }
+
+    pub fn history(&self, tab: &str) -> Result<HistoryInfo, DaemonError> {
+        let reply = self.call(&DaemonCommand::History {
+            tab: tab.to_string(),
+        })?;
+        reply.history.ok_or_else(|| DaemonError::InvalidReply {
+            reason: "reply is missing the history".to_string(),
+        })
+    }
+
+    /// Every control in the tab, only those activated with `method` if it
+    /// is given.
+    pub fn controls(
+        &self,
+        tab: &str,
+        method: Option<&str>,
+    ) -> Result<Vec<ControlInfo>, DaemonError> {
+        let reply = self.call(&DaemonCommand::Controls {
+            tab: tab.to_string(),
+            method: method.map(str::to_string),
+        })?;
+        reply.controls.ok_or_else(|| DaemonError::InvalidReply {
+            reason: "reply is missing the control list".to_string(),
+        })
+    }
+
+    pub fn tabs(&self) -> Result<Vec<TabInfo>, DaemonError> {
+        let reply = self.call(&DaemonCommand::Tabs)?;
+        reply.tabs.ok_or_else(|| DaemonError::InvalidReply {
+            reason: "reply is missing the tab list".to_string(),
+        })
+    }
+
+    pub fn shutdown(&self) -> Result<(), DaemonError> {
+        self.call(&DaemonCommand::Shutdown)?;
+        Ok(())
+    }
+}
+
+/// Snapshots streamed by `MechClient::watch`.
+///
+/// Yields one item per change until the daemon ends the stream, which it
+/// only does with an error (e.g. the tab was closed).
+pub struct Watch {
+    stream: UnixStream,
+    done: bool,
+}
+
+impl Iterator for Watch {
+    type Item = Result<Node, DaemonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = read_reply(&mut self.stream);
+        self.done = !matches!(result, Ok((_, true)));
+        Some(result.and_then(|(reply, _)| expect_hypermap(reply)))
+    }
+}
+
+/// Read one reply, returning its parameters and whether more follow.
+fn read_reply(stream: &mut UnixStream) -> Result<(DaemonOk, bool), DaemonError> {
+    let buf = read_message(stream).map_err(|e| DaemonError::InvalidReply {
+        reason: e.to_string(),
+    })?;
+    match serde_json::from_slice::<DaemonReply>(&buf) {
+        Ok(DaemonReply::Ok {
+            parameters,
+            continues,
+        }) => Ok((parameters, continues)),
+        Ok(DaemonReply::Err(err)) => Err(err),
+        Err(e) => Err(DaemonError::InvalidReply {
+            reason: e.to_string(),
+        }),
+    }
+}
+
+fn expect_tab(reply: DaemonOk) -> Result<TabInfo, DaemonError> {
+    reply.tab.ok_or_else(|| DaemonError::InvalidReply {
+        reason: "reply is missing the tab".to_string(),
+    })
+}
+
+fn expect_hypermap(reply: DaemonOk) -> Result<Node, DaemonError> {
+    let value = reply.hypermap.ok_or_else(|| DaemonError::InvalidReply {
+        reason: "reply is missing the hypermap".to_string(),
+    })?;
+    Node::from_value(value).map_err(|e| DaemonError::InvalidReply {
+        reason: e.to_string(),
+    })
+}
+
+fn expect_url(reply: DaemonOk) -> Result<String, DaemonError> {
+    reply.url.ok_or_else(|| DaemonError::InvalidReply {
+        reason: "reply is missing the url".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoadState;
+    use std::os::unix::net::UnixListener;
+    use std::thread::JoinHandle;
+
+    /// Serve a single connection with a canned reply, returning the command
+    /// the client sent.
+    fn fake_daemon(reply: &'static str) -> (tempfile::TempDir, MechClient, JoinHandle<Value>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mech.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let msg = read_message(&mut stream).unwrap();
+            write_message(&mut stream, reply.as_bytes()).unwrap();
+            serde_json::from_slice(&msg).unwrap()
+        });
+        (dir, MechClient::with_socket(path), handle)
+    }
+
+    #[test]
+    fn open_returns_tab_info() {
+        let (_dir, client, server) = fake_daemon(
+            r#"{"parameters": {"message": "Opened tab 1", "tab": {"id": 1, "name": "main", "url": "https://example.com", "state": "loading", "backend": "servo"}}}"#,
+        );
+        let tab = client.open("example.com", Some("main")).unwrap();
+        assert_eq!(
+            tab,
+            TabInfo {
+                id: 1,
+                name: Some("main".into()),
+                url: "https://example.com".into(),
+                state: LoadState::Loading,
+                backend: Backend::Servo,
+            }
+        );
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Open");
+        assert_eq!(sent["parameters"]["url"], "example.com");
+        assert_eq!(sent["parameters"]["name"], "main");
+        assert_eq!(sent["parameters"]["timeout_ms"], Value::Null);
+        assert_eq!(sent["parameters"]["backend"], Value::Null);
+    }
+
+    #[test]
+    fn timeout_and_backend_are_sent() {
+        let (_dir, client, server) = fake_daemon(
+            r#"{"parameters": {"tab": {"id": 1, "url": "https://example.com/", "state": "complete", "backend": "native"}}}"#,
+        );
+        client
+            .with_timeout(Duration::from_secs_f64(2.5))
+            .with_backend(Backend::Native)
+            .open("example.com", None)
+            .unwrap();
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["parameters"]["timeout_ms"], 2500);
+        assert_eq!(sent["parameters"]["backend"], "native");
+    }
+
+    #[test]
+    fn open_without_tab_is_invalid_reply() {
+        let (_dir, client, _server) = fake_daemon(r#"{"parameters": {"message": "Opened"}}"#);
+        assert!(matches!(
+            client.open("example.com", None),
+            Err(DaemonError::InvalidReply { .. })
+        ));
+    }
+
+    #[test]
+    fn show_returns_hypermap() {
+        let (_dir, client, server) = fake_daemon(
+            r##"{"parameters": {"hypermap": {"price": 42, "buy": {"#": {"href": "buy", "method": "POST"}}}}}"##,
+        );
+        let node = client.show("stocks", Some("market/acrn")).unwrap();
+        assert_eq!(node.at_path("price").unwrap().to_json(), 42);
+        let buy = node.at_path("buy").unwrap().as_map().unwrap();
+        assert_eq!(buy.attributes.href.as_deref(), Some("buy"));
+        assert_eq!(buy.attributes.method(), "POST");
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Show");
+        assert_eq!(sent["parameters"]["path"], "market/acrn");
+    }
+
+    #[test]
+    fn inspect_returns_the_page_url() {
+        let (_dir, client, _server) = fake_daemon(
+            r##"{"parameters": {"hypermap": {"buy": {"#": {"href": "buy"}}}, "url": "https://example.com/stocks/"}}"##,
+        );
+        let (node, url) = client.inspect("stocks", None).unwrap();
+        assert!(node.at_path("buy").unwrap().is_control());
+        assert_eq!(url.as_str(), "https://example.com/stocks/");
+
+        let (_dir, client, _server) = fake_daemon(r#"{"parameters": {"hypermap": {}}}"#);
+        assert!(matches!(
+            client.inspect("stocks", None),
+            Err(DaemonError::InvalidReply { .. })
+        ));
+    }
+
+    #[test]
+    fn show_with_malformed_hypermap_is_invalid_reply() {
+        let (_dir, client, _server) =
+            fake_daemon(r##"{"parameters": {"hypermap": {"buy": {"#": {"href": 7}}}}}"##);
+        assert!(matches!(
+            client.show("stocks", None),
+            Err(DaemonError::InvalidReply { .. })
+        ));
+    }
+
+    #[test]
+    fn tabs_returns_tab_list() {
+        let (_dir, client, _server) = fake_daemon(
+            r#"{"parameters": {"tabs": [{"id": 1, "url": "https://example.com/", "state": "complete", "backend": "servo"}]}}"#,
+        );
+        let tabs = client.tabs().unwrap();
+        assert_eq!(tabs.len(), 1);
+        assert_eq!(tabs[0].name, None);
+        assert_eq!(tabs[0].state, LoadState::Complete);
+    }
+
+    #[test]
+    fn daemon_errors_are_returned() {
+        let (_dir, client, _server) =
+            fake_daemon(r#"{"error": "TabNotFound", "parameters": {"tab": "7"}}"#);
+        match client.close("7") {
+            Err(DaemonError::TabNotFound { tab }) => assert_eq!(tab, "7"),
+            other => panic!("Expected TabNotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn use_control_sends_data() {
+        let (_dir, client, server) =
+            fake_daemon(r#"{"parameters": {"url": "https://example.com/orders/7"}}"#);
+        let mut data = HashMap::new();
+        data.insert("quantity".to_string(), "5".into());
+        let url = client.use_control("1", "buy", &data).unwrap();
+        assert_eq!(url, "https://example.com/orders/7");
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Use");
+        assert_eq!(sent["parameters"]["data"]["quantity"], "5");
+    }
+
+    #[test]
+    fn wait_sends_condition() {
+        let (_dir, client, server) = fake_daemon(r#"{"parameters": {"hypermap": "filled"}}"#);
+        let value = client
+            .with_timeout(Duration::from_secs(10))
+            .wait(
+                "orders",
+                "order/status",
+                WaitCondition::Equals {
+                    value: "filled".into(),
+                },
+            )
+            .unwrap();
+        assert_eq!(value.to_json(), "filled");
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Wait");
+        assert_eq!(sent["parameters"]["condition"]["kind"], "equals");
+        assert_eq!(sent["parameters"]["timeout_ms"], 10000);
+    }
+
+    #[test]
+    fn watch_streams_until_final_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mech.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let msg = read_message(&mut stream).unwrap();
+            for reply in [
+                r#"{"parameters": {"hypermap": "100.50"}, "continues": true}"#,
+                r#"{"parameters": {"hypermap": "101.25"}, "continues": true}"#,
+                r#"{"error": "TabNotFound", "parameters": {"tab": "stocks"}}"#,
+            ] {
+                write_message(&mut stream, reply.as_bytes()).unwrap();
+            }
+            serde_json::from_slice::<Value>(&msg).unwrap()
+        });
+
+        let client = MechClient::with_socket(path);
+        let updates: Vec<_> = client.watch("stocks", Some("market/acrn/price")).unwrap().collect();
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0].as_ref().unwrap().to_json(), "100.50");
+        assert_eq!(updates[1].as_ref().unwrap().to_json(), "101.25");
+        assert!(matches!(updates[2], Err(DaemonError::TabNotFound { .. })));
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Watch");
+        assert_eq!(sent["more"], true);
+    }
+
+    #[test]
+    fn history_returns_entries() {
+        let (_dir, client, server) = fake_daemon(
+            r#"{"parameters": {"history": {"entries": ["https://example.com/", "https://example.com/stocks"], "current": 1}}}"#,
+        );
+        let history = client.history("1").unwrap();
+        assert_eq!(history.entries.len(), 2);
+        assert_eq!(history.current, 1);
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "History");
+    }
+
+    #[test]
+    fn preview_returns_the_request() {
+        let (_dir, client, server) = fake_daemon(
+            r#"{"parameters": {"request": {"method": "POST", "url": "https://example.com/orders/", "body": {"quantity": 5}}}}"#,
+        );
+        let data = HashMap::from([("quantity".to_string(), Value::from(5))]);
+        let request = client.preview("stocks", "buy", &data).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body, Some(serde_json::json!({"quantity": 5})));
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Preview");
+        assert_eq!(sent["parameters"]["data"]["quantity"], 5);
+    }
+
+    #[test]
+    fn controls_sends_the_method_filter() {
+        let (_dir, client, server) = fake_daemon(
+            r#"{"parameters": {"controls": [{"path": "nav/home", "method": "GET", "target": "https://example.com/"}]}}"#,
+        );
+        let controls = client.controls("1", Some("GET")).unwrap();
+        assert_eq!(controls.len(), 1);
+        assert_eq!(controls[0].path, "nav/home");
+
+        let sent = server.join().unwrap();
+        assert_eq!(sent["method"], "Controls");
+        assert_eq!(sent["parameters"]["method"], "GET");
+    }
+
+    #[test]
+    fn missing_daemon_is_connection_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = MechClient::with_socket(dir.path().join("absent.sock"));
+        assert!(matches!(
+            client.tabs(),
+            Err(DaemonError::ConnectionFailed { .. })
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+// Fetching HyperMap resources over plain HTTP
+//
+// What a user agent without a browser engine needs: content negotiation, and
+// the spec's "activate a control" steps turned into an HTTP request. Used by
+// `mech validate` and by mechd's native backend.
+
+use std::fmt;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+use ureq::http;
+use ureq::{Agent, ResponseExt, SendBody};
+use url::Url;
+
+use crate::hypermap::{Hypermap, MapNode, Node, ParseError, HYPERMAP_MEDIA_TYPE};
+use crate::DEFAULT_TIMEOUT_MS;
+
+/// Shared by every request so that connections are reused. A server that
+/// stalls fails the request once mech would have stopped waiting for it.
+static AGENT: LazyLock<Agent> = LazyLock::new(|| {
+    let timeout = Some(Duration::from_millis(DEFAULT_TIMEOUT_MS));
+    Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(timeout)
+        .timeout_connect(timeout)
+        .build()
+        .into()
+});
+
+/// A request for a resource, or the one made by activating a control.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub url: Url,
+    /// The form fields as JSON, sent for every method but GET.
+    pub body: Option<Value>,
+}
+
+impl Request {
+    pub fn get(url: Url) -> Self {
+        Request {
+            method: "GET".to_string(),
+            url,
+            body: None,
+        }
+    }
+
+    /// The request that activating `control` makes, with its `href`
+    /// resolved against `base`. GET sends the control's fields as a query
+    /// string, every other method as a JSON body.
+    pub fn for_control(control: &MapNode, base: &Url) -> Result<Self, FetchError> {
+        let Some(href) = &control.attributes.href else {
+            return Err(FetchError::NotAControl);
+        };
+        let mut url = base.join(href).map_err(|e| FetchError::InvalidUrl {
+            url: href.clone(),
+            reason: e.to_string(),
+        })?;
+        // Browsers send a declared `post` as POST too
+        let method = control.attributes.method().to_ascii_uppercase();
+
+        if method == "GET" {
+            if !control.is_empty() {
+                let mut query = url.query_pairs_mut();
+                for (key, node) in control.iter() {
+                    query.append_pair(key, &query_value(node));
+                }
+            }
+            return Ok(Request {
+                method,
+                url,
+                body: None,
+            });
+        }
+
+        let fields: Map<String, Value> = control
+            .iter()
+            .map(|(key, node)| (key.to_string(), node.to_json()))
+            .collect();
+        Ok(Request {
+            method,
+            url,
+            body: Some(Value::Object(fields)),
+        })
+    }
+}
+
+/// How a field is written in a query string: strings as they are, `null` as
+/// nothing, and everything else as compact JSON.
+fn query_value(node: &Node) -> String {
+    match node.to_json() {
+        Value::String(s) => s,
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// A successful (2xx) response.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    /// Where the response came from after following redirects, which is the
+    /// resource's base URL.
+    pub url: Url,
+    pub content_type: String,
+    pub body: String,
+}
+
+impl Response {
+    /// Whether the response was served as `application/vnd.hypermap+json`.
+    pub fn is_hypermap(&self) -> bool {
+        let media_type = self.content_type.split(';').next().unwrap_or("").trim();
+        media_type.eq_ignore_ascii_case(HYPERMAP_MEDIA_TYPE)
+    }
+
+    pub fn hypermap(&self) -> Result<Hypermap, ParseError> {
+        Hypermap::from_json(&self.body)
+    }
+}
+
+/// Why a resource could not be fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchError {
+    /// The node has no `href` to activate.
+    NotAControl,
+    InvalidUrl { url: String, reason: String },
+    /// The server could not be reached or the connection failed.
+    Network { url: String, reason: String },
+    /// The server answered with something other than a 2xx response.
+    Status { url: String, status: u16 },
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::NotAControl => write!(f, "Not a control (no href)"),
+            FetchError::InvalidUrl { url, reason } => {
+                write!(f, "Invalid URL '{}': {}", url, reason)
+            }
+            FetchError::Network { url, reason } => write!(f, "Failed to fetch {}: {}", url, reason),
+            FetchError::Status { url, status } => {
+                let reason = http::StatusCode::from_u16(*status)
+                    .ok()
+                    .and_then(|s| s.canonical_reason())
+                    .unwrap_or("");
+                write!(f, "{} responded {} {}", url, status, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Send `request` asking for HyperMap, following redirects. Redirects after
+/// a POST continue as GET, as browsers do.
+pub fn fetch(request: &Request) -> Result<Response, FetchError> {
+    fetch_accepting(request, HYPERMAP_MEDIA_TYPE)
+}
+
+/// Like `fetch`, but asking for the media types in `accept`.
+pub fn fetch_accepting(request: &Request, accept: &str) -> Result<Response, FetchError> {
+    let network_error = |e: ureq::Error| FetchError::Network {
+        url: request.url.to_string(),
+        reason: e.to_string(),
+    };
+    let builder = http::Request::builder()
+        .method(request.method.as_str())
+        .uri(request.url.as_str())
+        .header("Accept", accept);
+    let result = match &request.body {
+        Some(body) => {
+            let request = builder
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+                .map_err(|e| network_error(e.into()))?;
+            AGENT.run(request)
+        }
+        None => {
+            let request = builder
+                .body(SendBody::none())
+                .map_err(|e| network_error(e.into()))?;
+            AGENT.run(request)
+        }
+    };
+    let mut response = result.map_err(network_error)?;
+
+    let url = Url::parse(&response.get_uri().to_string()).unwrap_or(request.url.clone());
+    let status = response.status().as_u16();
+    if !response.status().is_success() {
+        return Err(FetchError::Status {
+            url: url.to_string(),
+            status,
+        });
+    }
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let body = response.body_mut().read_to_string().map_err(network_error)?;
+    Ok(Response {
+        status,
+        url,
+        content_type,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn control(value: Value) -> MapNode {
+        Node::from_value(value).unwrap().as_map().unwrap().clone()
+    }
+
+    fn base() -> Url {
+        Url::parse("https://example.com/stocks/").unwrap()
+    }
+
+    #[test]
+    fn get_sends_fields_as_query() {
+        let search = control(json!({
+            "#": {"href": "search?page=2"},
+            "q": "acorn computers",
+            "limit": 10,
+            "exact": null
+        }));
+        let request = Request::for_control(&search, &base()).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(
+            request.url.as_str(),
+            "https://example.com/stocks/search?page=2&q=acorn+computers&limit=10&exact="
+        );
+        assert_eq!(request.body, None);
+
+        let link = control(json!({"#": {"href": "/"}}));
+        let request = Request::for_control(&link, &base()).unwrap();
+        assert_eq!(request.url.as_str(), "https://example.com/");
+    }
+
+    #[test]
+    fn other_methods_send_a_json_body() {
+        let buy = control(json!({
+            "#": {"href": "acrn/orders/", "method": "post"},
+            "quantity": 5,
+            "limit": {"price": 200.5}
+        }));
+        let request = Request::for_control(&buy, &base()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.url.as_str(), "https://example.com/stocks/acrn/orders/");
+        assert_eq!(request.body, Some(json!({"quantity": 5, "limit": {"price": 200.5}})));
+
+        let delete = control(json!({"#": {"href": "acrn", "method": "DELETE"}}));
+        let request = Request::for_control(&delete, &base()).unwrap();
+        assert_eq!(request.body, Some(json!({})));
+    }
+
+    #[test]
+    fn only_controls_can_be_activated() {
+        let plain = control(json!({"quantity": 5}));
+        assert_eq!(Request::for_control(&plain, &base()), Err(FetchError::NotAControl));
+
+        let broken = control(json!({"#": {"href": "http://[::1"}}));
+        assert!(matches!(
+            Request::for_control(&broken, &base()),
+            Err(FetchError::InvalidUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn content_type_check_ignores_parameters() {
+        let response = Response {
+            status: 200,
+            url: base(),
+            content_type: "application/vnd.hypermap+json; charset=utf-8".to_string(),
+            body: "{}".to_string(),
+        };
+        assert!(response.is_hypermap());
+        let html = Response {
+            content_type: "text/html".to_string(),
+            ..response
+        };
+        assert!(!html.is_hypermap());
+    }
+}
@@ -0,0 +1,899 @@
+// HyperMap data model
+//
+// Rust counterparts of the spec's node tree: `Hypermap`, `MapNode`,
+// `ListNode` and `ValueNode`. Parsing lifts each object's `#` key out into
+// `Attributes`, so callers never see it as data. `to_json` is lossless and
+// parses back into an equal tree; `to_view` is the lossy form the spec
+// defines for consumers, where a control's `#` becomes `{"type": "control"}`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use serde_json::{Map, Value};
+
+/// The media type HyperMap resources are served with.
+pub const HYPERMAP_MEDIA_TYPE: &str = "application/vnd.hypermap+json";
+
+/// The members of a map's `#` key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// Target URL, absolute or relative to the resource's base URL. A map
+    /// with an `href` is a control.
+    pub href: Option<String>,
+    /// HTTP method as declared; `method()` supplies the default.
+    pub method: Option<String>,
+    /// URLs of the scripts the map declares.
+    pub scripts: Option<Vec<String>>,
+    /// Members the spec does not define, kept as they were sent so that
+    /// `to_json` writes them back out.
+    pub extra: Map<String, Value>,
+}
+
+impl Attributes {
+    /// The HTTP method to activate the control with, `GET` unless declared.
+    pub fn method(&self) -> &str {
+        self.method.as_deref().unwrap_or("GET")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.href.is_none()
+            && self.method.is_none()
+            && self.scripts.is_none()
+            && self.extra.is_empty()
+    }
+
+    fn from_value(value: Value, path: &[String]) -> Result<Self, ParseError> {
+        let invalid = |reason: &str| ParseError::InvalidAttributes {
+            path: path.iter().fold(String::new(), |path, key| join_path(&path, key)),
+            reason: reason.to_string(),
+        };
+        let Value::Object(mut members) = value else {
+            return Err(invalid("'#' must be an object"));
+        };
+        let mut string_member = |name: &str| match members.remove(name) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(invalid(&format!("'{}' must be a string", name))),
+        };
+        let href = string_member("href")?;
+        let method = string_member("method")?;
+        let scripts = match members.remove("scripts") {
+            None => None,
+            Some(Value::Array(items)) => Some(
+                items
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::String(s) => Ok(s),
+                        _ => Err(invalid("'scripts' must be an array of strings")),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some(_) => return Err(invalid("'scripts' must be an array of strings")),
+        };
+        Ok(Attributes {
+            href,
+            method,
+            scripts,
+            extra: members,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut members = Map::new();
+        if let Some(href) = &self.href {
+            members.insert("href".to_string(), Value::String(href.clone()));
+        }
+        if let Some(method) = &self.method {
+            members.insert("method".to_string(), Value::String(method.clone()));
+        }
+        if let Some(scripts) = &self.scripts {
+            members.insert("scripts".to_string(), scripts.clone().into());
+        }
+        members.extend(self.extra.clone());
+        Value::Object(members)
+    }
+}
+
+/// Why a JSON document could not be turned into a node tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The text is not JSON.
+    InvalidJson { reason: String },
+    /// The top level of a resource is not an object.
+    NotAnObject,
+    /// The `#` key of the map at `path` is malformed.
+    InvalidAttributes { path: String, reason: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidJson { reason } => write!(f, "Invalid JSON: {}", reason),
+            ParseError::NotAnObject => write!(f, "The top level of a hypermap must be an object"),
+            ParseError::InvalidAttributes { path, reason } if path.is_empty() => {
+                write!(f, "Invalid attributes at the root: {}", reason)
+            }
+            ParseError::InvalidAttributes { path, reason } => {
+                write!(f, "Invalid attributes at '{}': {}", path, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Any node in a hypermap tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Map(MapNode),
+    List(ListNode),
+    Value(ValueNode),
+}
+
+impl Node {
+    /// Build a tree from any JSON value, including the `to_json` output of
+    /// a subtree.
+    pub fn from_value(value: Value) -> Result<Self, ParseError> {
+        parse_node(value, &mut Vec::new())
+    }
+
+    pub fn as_map(&self) -> Option<&MapNode> {
+        match self {
+            Node::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&ListNode> {
+        match self {
+            Node::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_value(&self) -> Option<&ValueNode> {
+        match self {
+            Node::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_value_mut(&mut self) -> Option<&mut ValueNode> {
+        match self {
+            Node::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a map with an `href`.
+    pub fn is_control(&self) -> bool {
+        self.as_map().is_some_and(MapNode::is_control)
+    }
+
+    /// The child under `key`: a map key, or a list index (negative indices
+    /// count from the end). Values have no children.
+    pub fn child(&self, key: &str) -> Option<&Node> {
+        match self {
+            Node::Map(map) => map.at(key),
+            Node::List(list) => list.at(key.parse().ok()?),
+            Node::Value(_) => None,
+        }
+    }
+
+    pub fn child_mut(&mut self, key: &str) -> Option<&mut Node> {
+        match self {
+            Node::Map(map) => map.at_mut(key),
+            Node::List(list) => list.at_mut(key.parse().ok()?),
+            Node::Value(_) => None,
+        }
+    }
+
+    /// Descend one key or index per element of `path`, as the spec's
+    /// `nodeFromPath()` does. An empty path returns this node.
+    pub fn node_from_path<S: AsRef<str>>(&self, path: &[S]) -> Option<&Node> {
+        path.iter().try_fold(self, |node, key| node.child(key.as_ref()))
+    }
+
+    pub fn node_from_path_mut<S: AsRef<str>>(&mut self, path: &[S]) -> Option<&mut Node> {
+        path.iter().try_fold(self, |node, key| node.child_mut(key.as_ref()))
+    }
+
+    /// Look up a slash-separated path such as `market/acrn/price`, the form
+    /// used in `TAB:PATH` targets, as `path_keys` reads it.
+    pub fn at_path(&self, path: &str) -> Option<&Node> {
+        self.node_from_path(&path_keys(path)?)
+    }
+
+    pub fn at_path_mut(&mut self, path: &str) -> Option<&mut Node> {
+        self.node_from_path_mut(&path_keys(path)?)
+    }
+
+    /// Feed `value` into the field at `path`, as `Hypermap.input()` does.
+    /// Returns whether there was a value there to change; arrays and
+    /// objects are not inputs and change nothing.
+    pub fn input(&mut self, path: &str, value: impl Into<Value>) -> bool {
+        let value = value.into();
+        if matches!(value, Value::Array(_) | Value::Object(_)) {
+            return false;
+        }
+        match self.at_path_mut(path).and_then(Node::as_value_mut) {
+            Some(field) => {
+                field.value = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Lossless JSON, with every `#` key restored.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Node::Map(map) => map.to_json(),
+            Node::List(list) => list.to_json(),
+            Node::Value(value) => value.to_json(),
+        }
+    }
+
+    /// Lossy JSON for consumers: controls keep only a `{"type": "control"}`
+    /// marker and other attributes are dropped.
+    pub fn to_view(&self) -> Value {
+        match self {
+            Node::Map(map) => map.to_view(),
+            Node::List(list) => list.to_view(),
+            Node::Value(value) => value.to_view(),
+        }
+    }
+
+    /// Every control at or below this node in document order, with its
+    /// slash-separated path relative to this node (empty for the node
+    /// itself).
+    pub fn controls(&self) -> Vec<(String, &MapNode)> {
+        let mut controls = Vec::new();
+        collect_controls(self, String::new(), &mut controls);
+        controls
+    }
+}
+
+fn collect_controls<'a>(node: &'a Node, path: String, controls: &mut Vec<(String, &'a MapNode)>) {
+    let child_path = |key: &str| join_path(&path, key);
+    match node {
+        Node::Map(map) => {
+            if map.is_control() {
+                controls.push((path.clone(), map));
+            }
+            for (key, child) in map.iter() {
+                collect_controls(child, child_path(key), controls);
+            }
+        }
+        Node::List(list) => {
+            for (i, child) in list.iter().enumerate() {
+                collect_controls(child, child_path(&i.to_string()), controls);
+            }
+        }
+        Node::Value(_) => {}
+    }
+}
+
+/// Split a path into the keys it names. Within a key, `~1` stands for `/`
+/// and `~0` for `~`, as in a JSON Pointer (RFC 6901), and any other `~`
+/// makes the path invalid. A path that starts with `/` is read as a JSON
+/// Pointer, so that every segment counts and `/` alone names the empty key.
+/// Otherwise empty segments are ignored: `market//acrn/` is `market/acrn`.
+pub fn path_keys(path: &str) -> Option<Vec<String>> {
+    let segments: Vec<&str> = match path.strip_prefix('/') {
+        Some(pointer) => pointer.split('/').collect(),
+        None => path.split('/').filter(|s| !s.is_empty()).collect(),
+    };
+    segments.into_iter().map(unescape_key).collect()
+}
+
+pub(crate) fn unescape_key(segment: &str) -> Option<String> {
+    let mut key = String::with_capacity(segment.len());
+    let mut chars = segment.chars();
+    while let Some(c) = chars.next() {
+        key.push(match c {
+            '~' => match chars.next()? {
+                '0' => '~',
+                '1' => '/',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(key)
+}
+
+/// Escape `key` for use as one segment of a path, as JSON Pointer does.
+pub fn escape_key(key: &str) -> Cow<'_, str> {
+    if key.contains(['~', '/']) {
+        Cow::Owned(key.replace('~', "~0").replace('/', "~1"))
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+/// Append `key` to `path`, escaping it. Only a JSON Pointer can name an
+/// empty key, so the result is one if `path` already is or `key` is empty.
+pub fn join_path(path: &str, key: &str) -> String {
+    let key = escape_key(key);
+    if path.starts_with('/') {
+        format!("{}/{}", path, key)
+    } else if key.is_empty() {
+        let segments = path.split('/').filter(|s| !s.is_empty());
+        segments.chain([""]).map(|segment| format!("/{}", segment)).collect()
+    } else if path.is_empty() {
+        key.into_owned()
+    } else {
+        format!("{}/{}", path, key)
+    }
+}
+
+/// What a number JSON cannot carry is sent as, in a `{"$number": name}`
+/// object that stands for the number.
+const NUMBER_TAGS: [&str; 4] = ["NaN", "Infinity", "-Infinity", "-0"];
+
+/// The name of the number `value` stands for, if it is a `{"$number": name}`
+/// tag as mechd sends NaN, the infinities and -0.
+pub fn number_tag(value: &Value) -> Option<&str> {
+    let Value::Object(members) = value else {
+        return None;
+    };
+    match members.get("$number") {
+        Some(Value::String(name)) if members.len() == 1 => {
+            NUMBER_TAGS.contains(&name.as_str()).then_some(name.as_str())
+        }
+        _ => None,
+    }
+}
+
+fn parse_node(value: Value, path: &mut Vec<String>) -> Result<Node, ParseError> {
+    match value {
+        tagged if number_tag(&tagged).is_some() => Ok(Node::Value(ValueNode::new(tagged))),
+        Value::Object(members) => parse_map(members, path).map(Node::Map),
+        Value::Array(items) => {
+            let mut list = ListNode::default();
+            for (i, item) in items.into_iter().enumerate() {
+                path.push(i.to_string());
+                let child = parse_node(item, path);
+                path.pop();
+                list.append(child?);
+            }
+            Ok(Node::List(list))
+        }
+        primitive => Ok(Node::Value(ValueNode::new(primitive))),
+    }
+}
+
+fn parse_map(
+    mut members: Map<String, Value>,
+    path: &mut Vec<String>,
+) -> Result<MapNode, ParseError> {
+    let attributes = match members.shift_remove("#") {
+        Some(value) => Attributes::from_value(value, path)?,
+        None => Attributes::default(),
+    };
+    let mut map = MapNode::new(attributes);
+    for (key, value) in members {
+        path.push(key.clone());
+        let child = parse_node(value, path);
+        path.pop();
+        map.set(key, child?);
+    }
+    Ok(map)
+}
+
+/// An ordered map of keys to nodes, from a JSON object.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapNode {
+    pub attributes: Attributes,
+    entries: Vec<(String, Node)>,
+}
+
+impl MapNode {
+    pub fn new(attributes: Attributes) -> Self {
+        MapNode {
+            attributes,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Whether the map's attributes include an `href`.
+    pub fn is_control(&self) -> bool {
+        self.attributes.href.is_some()
+    }
+
+    pub fn has(&self, key: &str) -> bool {
+        self.at(key).is_some()
+    }
+
+    pub fn at(&self, key: &str) -> Option<&Node> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn at_mut(&mut self, key: &str) -> Option<&mut Node> {
+        self.entries.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Set the entry for `key`, keeping its position if it already exists.
+    pub fn set(&mut self, key: impl Into<String>, value: Node) -> &mut Self {
+        let key = key.into();
+        match self.at_mut(&key) {
+            Some(existing) => *existing = value,
+            None => self.entries.push((key, value)),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.entries.retain(|(k, _)| k != key);
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries in order, without the `#` key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Node)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        if !self.attributes.is_empty() {
+            object.insert("#".to_string(), self.attributes.to_json());
+        }
+        for (key, value) in self.iter() {
+            object.insert(key.to_string(), value.to_json());
+        }
+        Value::Object(object)
+    }
+
+    pub fn to_view(&self) -> Value {
+        let mut object = Map::new();
+        if self.is_control() {
+            let mut marker = Map::new();
+            marker.insert("type".to_string(), Value::String("control".to_string()));
+            object.insert("#".to_string(), Value::Object(marker));
+        }
+        for (key, value) in self.iter() {
+            object.insert(key.to_string(), value.to_view());
+        }
+        Value::Object(object)
+    }
+}
+
+/// An ordered sequence of nodes, from a JSON array.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListNode {
+    items: Vec<Node>,
+}
+
+impl ListNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The node at `index`; negative indices count from the end.
+    pub fn at(&self, index: isize) -> Option<&Node> {
+        self.items.get(self.resolve(index)?)
+    }
+
+    pub fn at_mut(&mut self, index: isize) -> Option<&mut Node> {
+        let index = self.resolve(index)?;
+        self.items.get_mut(index)
+    }
+
+    pub(crate) fn resolve(&self, index: isize) -> Option<usize> {
+        if index < 0 {
+            self.items.len().checked_sub(index.unsigned_abs())
+        } else {
+            Some(index as usize)
+        }
+    }
+
+    /// Replace the node at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, value: Node) -> &mut Self {
+        self.items[index] = value;
+        self
+    }
+
+    pub fn append(&mut self, value: Node) -> &mut Self {
+        self.items.push(value);
+        self
+    }
+
+    pub fn prepend(&mut self, value: Node) -> &mut Self {
+        self.items.insert(0, value);
+        self
+    }
+
+    /// Insert at `index`, shifting later nodes along.
+    ///
+    /// Panics if `index` is greater than the list's size.
+    pub fn insert(&mut self, index: usize, value: Node) -> &mut Self {
+        self.items.insert(index, value);
+        self
+    }
+
+    /// Remove the node at `index`, if there is one.
+    pub fn delete(&mut self, index: usize) -> &mut Self {
+        if index < self.items.len() {
+            self.items.remove(index);
+        }
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Node> {
+        self.items.iter()
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.iter().map(Node::to_json).collect())
+    }
+
+    pub fn to_view(&self) -> Value {
+        Value::Array(self.iter().map(Node::to_view).collect())
+    }
+}
+
+/// A single primitive: null, a boolean, a number or a string. A number
+/// JSON cannot carry is held as its `number_tag`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueNode {
+    pub value: Value,
+}
+
+impl ValueNode {
+    /// Panics if `value` is an array or an object other than a number tag.
+    pub fn new(value: impl Into<Value>) -> Self {
+        let value = value.into();
+        assert!(
+            !matches!(value, Value::Array(_) | Value::Object(_)) || number_tag(&value).is_some(),
+            "a ValueNode holds only primitives"
+        );
+        ValueNode { value }
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.value.clone()
+    }
+
+    /// Identical to `to_json`; values have nothing to hide.
+    pub fn to_view(&self) -> Value {
+        self.value.clone()
+    }
+}
+
+/// A parsed HyperMap resource: a `MapNode` at the root of the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypermap {
+    /// Always a `Node::Map`, kept as a `Node` so that path lookups can
+    /// return the root itself.
+    root: Node,
+}
+
+impl Hypermap {
+    /// Parse a resource body, as the spec's `Hypermap.fromJSON()` does.
+    pub fn from_json(json: &str) -> Result<Self, ParseError> {
+        let value = serde_json::from_str(json).map_err(|e| ParseError::InvalidJson {
+            reason: e.to_string(),
+        })?;
+        Self::from_value(value)
+    }
+
+    pub fn from_value(value: Value) -> Result<Self, ParseError> {
+        let Value::Object(members) = value else {
+            return Err(ParseError::NotAnObject);
+        };
+        let root = parse_map(members, &mut Vec::new())?;
+        Ok(Hypermap {
+            root: Node::Map(root),
+        })
+    }
+
+    pub fn as_node(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn as_node_mut(&mut self) -> &mut Node {
+        &mut self.root
+    }
+
+    pub fn into_node(self) -> Node {
+        self.root
+    }
+}
+
+impl Default for Hypermap {
+    fn default() -> Self {
+        Hypermap {
+            root: Node::Map(MapNode::default()),
+        }
+    }
+}
+
+impl Deref for Hypermap {
+    type Target = MapNode;
+
+    fn deref(&self) -> &MapNode {
+        match &self.root {
+            Node::Map(map) => map,
+            _ => unreachable!("the root of a hypermap is always a map"),
+        }
+    }
+}
+
+impl DerefMut for Hypermap {
+    fn deref_mut(&mut self) -> &mut MapNode {
+        match &mut self.root {
+            Node::Map(map) => map,
+            _ => unreachable!("the root of a hypermap is always a map"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stocks() -> Value {
+        json!({
+            "#": {"href": "/stocks/", "scripts": ["/stocks.js"]},
+            "market": {
+                "acrn": {
+                    "ticker": "ACRN",
+                    "price": 12.5,
+                    "buy": {
+                        "#": {"href": "/stocks/acrn/buy", "method": "POST"},
+                        "quantity": 0
+                    }
+                }
+            },
+            "news": ["up", "down"]
+        })
+    }
+
+    #[test]
+    fn parse_lifts_attributes_out_of_the_data() {
+        let map = Hypermap::from_value(stocks()).unwrap();
+        assert_eq!(map.attributes.href.as_deref(), Some("/stocks/"));
+        assert_eq!(map.attributes.scripts, Some(vec!["/stocks.js".to_string()]));
+        assert!(!map.has("#"));
+
+        let buy = map.as_node().at_path("market/acrn/buy").unwrap();
+        assert!(buy.is_control());
+        let buy = buy.as_map().unwrap();
+        assert_eq!(buy.attributes.method(), "POST");
+        assert_eq!(buy.size(), 1);
+    }
+
+    #[test]
+    fn parse_keeps_key_order() {
+        let map = Hypermap::from_json(r##"{"b": 1, "#": {"href": "/"}, "a": 2, "c": 3}"##).unwrap();
+        let keys: Vec<_> = map.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["b", "a", "c"]);
+        let json = serde_json::to_string(&map.as_node().to_json()).unwrap();
+        assert_eq!(json, r##"{"#":{"href":"/"},"b":1,"a":2,"c":3}"##);
+    }
+
+    #[test]
+    fn controls_are_listed_in_document_order() {
+        let mut value = stocks();
+        value["news"] = json!([{"open": {"#": {"href": "/news/0"}, "like": {"#": {"href": "like"}}}}]);
+        let map = Hypermap::from_value(value).unwrap();
+        let controls = map.as_node().controls();
+        let paths: Vec<_> = controls.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["", "market/acrn/buy", "news/0/open", "news/0/open/like"]);
+        assert_eq!(controls[1].1.attributes.method(), "POST");
+
+        let acrn = map.as_node().at_path("market/acrn").unwrap();
+        let paths: Vec<_> = acrn.controls().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["buy"]);
+    }
+
+    #[test]
+    fn input_changes_only_values() {
+        let mut map = Hypermap::from_value(stocks()).unwrap();
+        let quantity = |map: &Hypermap| {
+            let node = map.as_node().at_path("market/acrn/buy/quantity");
+            node.unwrap().to_json()
+        };
+        assert!(map.as_node_mut().input("market/acrn/buy/quantity", "5"));
+        assert_eq!(quantity(&map), json!("5"));
+        assert!(map.as_node_mut().input("market/acrn/buy/quantity", 5));
+        assert_eq!(quantity(&map), json!(5));
+        assert!(!map.as_node_mut().input("market/acrn/buy/quantity", json!([5])));
+        assert_eq!(quantity(&map), json!(5));
+
+        assert!(!map.as_node_mut().input("market/acrn/buy", "5"));
+        assert!(!map.as_node_mut().input("market/acrn/sell", "5"));
+        assert!(map.as_node().at_path("market/acrn/buy").unwrap().is_control());
+    }
+
+    #[test]
+    fn method_defaults_to_get() {
+        let map = Hypermap::from_value(json!({"#": {"href": "/"}})).unwrap();
+        assert_eq!(map.attributes.method, None);
+        assert_eq!(map.attributes.method(), "GET");
+    }
+
+    #[test]
+    fn parse_rejects_non_object_top_level() {
+        assert_eq!(Hypermap::from_value(json!([1, 2])), Err(ParseError::NotAnObject));
+        assert!(matches!(
+            Hypermap::from_json("{"),
+            Err(ParseError::InvalidJson { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_attributes() {
+        let err = Hypermap::from_value(json!({"a": {"b": {"#": {"href": 5}}}})).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::InvalidAttributes {
+                path: "a/b".to_string(),
+                reason: "'href' must be a string".to_string(),
+            }
+        );
+        assert_eq!(err.to_string(), "Invalid attributes at 'a/b': 'href' must be a string");
+
+        let err = Hypermap::from_value(json!({"#": ["/"]})).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid attributes at the root: '#' must be an object");
+
+        let err = Node::from_value(json!([{"#": {"scripts": "/a.js"}}])).unwrap_err();
+        assert!(matches!(err, ParseError::InvalidAttributes { path, .. } if path == "0"));
+    }
+
+    #[test]
+    fn path_lookup() {
+        let map = Hypermap::from_value(stocks()).unwrap();
+        let root = map.as_node();
+        assert_eq!(root.at_path(""), Some(root));
+        assert_eq!(
+            root.at_path("market/acrn/price").unwrap().as_value().unwrap().value,
+            json!(12.5)
+        );
+        assert_eq!(root.at_path("market//acrn/ticker/").unwrap().to_json(), json!("ACRN"));
+        assert_eq!(root.at_path("/market/acrn/ticker").unwrap().to_json(), json!("ACRN"));
+        assert_eq!(root.node_from_path(&["news", "1"]).unwrap().to_json(), json!("down"));
+        assert_eq!(root.at_path("news/-1").unwrap().to_json(), json!("down"));
+        assert_eq!(root.at_path("news/2"), None);
+        assert_eq!(root.at_path("news/-3"), None);
+        assert_eq!(root.at_path("market/#"), None);
+        assert_eq!(root.at_path("market/acrn/price/x"), None);
+    }
+
+    #[test]
+    fn paths_escape_keys_like_json_pointers() {
+        let value = json!({"links": {"https://example.com/a": 1, "a~b": 2, "": {"": 3}}});
+        let root = Node::from_value(value).unwrap();
+        let at = |path| root.at_path(path).map(Node::to_json);
+        assert_eq!(at("links/https:~1~1example.com~1a"), Some(json!(1)));
+        assert_eq!(at("links/a~0b"), Some(json!(2)));
+        assert_eq!(at("/links//"), Some(json!(3)));
+        assert_eq!(at("links/a~b"), None);
+        assert_eq!(at("/links/a~0b/"), None);
+
+        assert_eq!(join_path("links", "https://example.com/a"), "links/https:~1~1example.com~1a");
+        assert_eq!(join_path("links", ""), "/links/");
+        assert_eq!(join_path("/links/", ""), "/links//");
+        assert_eq!(join_path("", "a~b"), "a~0b");
+    }
+
+    #[test]
+    fn path_lookup_mut() {
+        let mut map = Hypermap::from_value(stocks()).unwrap();
+        let quantity = map.as_node_mut().at_path_mut("market/acrn/buy/quantity").unwrap();
+        quantity.as_value_mut().unwrap().value = json!(7);
+        let news = map.as_node_mut().at_path_mut("news/-1").unwrap();
+        *news = Node::Value(ValueNode::new("flat"));
+        let root = map.as_node();
+        assert_eq!(root.at_path("market/acrn/buy/quantity").unwrap().to_json(), json!(7));
+        assert_eq!(root.at_path("news").unwrap().to_json(), json!(["up", "flat"]));
+    }
+
+    #[test]
+    fn to_json_round_trips() {
+        let map = Hypermap::from_value(stocks()).unwrap();
+        assert_eq!(map.as_node().to_json(), stocks());
+        assert_eq!(Hypermap::from_value(map.as_node().to_json()).unwrap(), map);
+    }
+
+    #[test]
+    fn unknown_attributes_round_trip() {
+        let value = json!({"home": {"#": {"href": "/", "title": "Home", "rel": ["up"]}}});
+        let node = Node::from_value(value.clone()).unwrap();
+        assert_eq!(node.to_json(), value);
+        let home = node.at_path("home").and_then(Node::as_map).unwrap();
+        assert_eq!(home.attributes.extra.get("title"), Some(&json!("Home")));
+        assert_eq!(node.to_view(), json!({"home": {"#": {"type": "control"}}}));
+    }
+
+    #[test]
+    fn to_view_marks_controls_only() {
+        let map = Hypermap::from_value(stocks()).unwrap();
+        assert_eq!(
+            map.as_node().to_view(),
+            json!({
+                "#": {"type": "control"},
+                "market": {
+                    "acrn": {
+                        "ticker": "ACRN",
+                        "price": 12.5,
+                        "buy": {"#": {"type": "control"}, "quantity": 0}
+                    }
+                },
+                "news": ["up", "down"]
+            })
+        );
+
+        let scripted = Hypermap::from_value(json!({"#": {"scripts": []}, "a": 1})).unwrap();
+        assert_eq!(scripted.as_node().to_view(), json!({"a": 1}));
+    }
+
+    #[test]
+    fn map_set_keeps_position() {
+        let mut map = MapNode::default();
+        map.set("a", Node::Value(ValueNode::new(1)))
+            .set("b", Node::Value(ValueNode::new(2)))
+            .set("a", Node::Value(ValueNode::new(3)));
+        let keys: Vec<&str> = map.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(map.at("a").unwrap().to_json(), json!(3));
+        map.delete("a");
+        assert!(!map.has("a"));
+        assert_eq!(map.size(), 1);
+    }
+
+    #[test]
+    fn list_edits() {
+        let mut list = ListNode::new();
+        list.append(Node::Value(ValueNode::new("b")))
+            .prepend(Node::Value(ValueNode::new("a")))
+            .insert(1, Node::Value(ValueNode::new("x")))
+            .set(2, Node::Value(ValueNode::new("c")));
+        assert_eq!(list.to_json(), json!(["a", "x", "c"]));
+        list.delete(1).delete(7);
+        assert_eq!(list.to_json(), json!(["a", "c"]));
+        assert_eq!(list.at(-2).unwrap().to_json(), json!("a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "only primitives")]
+    fn value_node_rejects_collections() {
+        ValueNode::new(json!([1]));
+    }
+
+    #[test]
+    fn number_tags_are_values() {
+        let value = json!({
+            "a": {"$number": "NaN"},
+            "b": {"$number": "nan"},
+            "c": {"$number": "-0", "x": 1}
+        });
+        let root = Node::from_value(value.clone()).unwrap();
+        let a = root.at_path("a").and_then(Node::as_value).unwrap();
+        assert_eq!(a.value, json!({"$number": "NaN"}));
+        assert!(root.at_path("b/$number").is_some());
+        assert!(root.at_path("c/x").is_some());
+        assert_eq!(root.to_json(), value);
+    }
+}
@@ -4,12 +4,30 @@
 // Communication uses the varlink protocol: JSON messages over a Unix socket,
 // framed with null byte (\0) delimiters.
 
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::fmt::{self, Write};
 use std::fs;
 use std::io;
+use url::Url;
+
+use crate::fetch::Request;
+
+pub mod client;
+pub mod fetch;
+pub mod hypermap;
+pub mod select;
+pub mod validate;
+
+pub use client::{MechClient, Watch};
+pub use hypermap::{
+    escape_key, join_path, number_tag, path_keys, Attributes, Hypermap, ListNode, MapNode, Node,
+    ParseError, ValueNode, HYPERMAP_MEDIA_TYPE,
+};
+pub use select::{is_selector, Selector, SelectorError};
+pub use validate::{validate, Violation};
 
 pub fn socket_path() -> String {
     std::env::var("MECH_SOCKET_PATH").unwrap_or_else(|_| "/tmp/mech.sock".to_string())
@@ -62,6 +80,9 @@ pub fn read_message(reader: &mut impl io::Read) -> io::Result<Vec<u8>> {
 
 // -- Protocol types -----------------------------------------------------------
 
+/// How long `Open` and `Use` wait for the page when no timeout is given.
+pub const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
 /// Daemon protocol commands (varlink methods).
 ///
 /// Serializes as `{"method": "Open", "parameters": {"url": "..."}}` which
@@ -69,28 +90,60 @@ pub fn read_message(reader: &mut impl io::Read) -> io::Result<Vec<u8>> {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "method", content = "parameters")]
 pub enum DaemonCommand {
+    /// Waits until the page has loaded and its hypermap is initialized,
+    /// up to `timeout_ms` (default `DEFAULT_TIMEOUT_MS`; 0 returns at once).
+    /// `backend` defaults to the one mechd was started with.
     Open {
         url: String,
         name: Option<String>,
+        backend: Option<Backend>,
+        timeout_ms: Option<u64>,
     },
     Show {
         tab: String,
         path: Option<String>,
-        color: bool,
     },
+    /// Replies with every node matching `selector`, as `Selector` reads it.
+    Select {
+        tab: String,
+        selector: String,
+    },
+    /// `value` is a string, number, boolean or null; older clients only
+    /// send strings.
     Set {
         tab: String,
         path: String,
-        value: String,
+        value: Value,
     },
+    /// Waits like `Open` when activating the control loads a new resource.
     Use {
         tab: String,
         path: String,
-        data: HashMap<String, String>,
+        data: HashMap<String, Value>,
+        timeout_ms: Option<u64>,
+    },
+    /// Replies with the request `Use` would make with the same arguments,
+    /// without changing the tab.
+    Preview {
+        tab: String,
+        path: String,
+        data: HashMap<String, Value>,
+    },
+    /// Replies with the value at `path` once it satisfies `condition`,
+    /// re-checking whenever the tab's hypermap mutates.
+    Wait {
+        tab: String,
+        path: String,
+        condition: WaitCondition,
+        timeout_ms: Option<u64>,
     },
+    /// Copies the tab's history up to the current page and the inputs made
+    /// to that page into a tab on the same backend, waiting for them to be
+    /// restored like `Open` waits.
     Fork {
         tab: String,
         name: Option<String>,
+        timeout_ms: Option<u64>,
     },
     Close {
         tab: String,
@@ -99,10 +152,60 @@ pub enum DaemonCommand {
         tab: String,
         name: String,
     },
+    /// Streams the value at `path` (or the whole hypermap) each time it
+    /// changes. Must be called with `more` set; every reply but an error
+    /// carries `continues`.
+    Watch {
+        tab: String,
+        path: Option<String>,
+    },
+    /// Load `url` into an existing tab, resolving it against the tab's
+    /// current URL, and wait like `Use`.
+    Goto {
+        tab: String,
+        url: String,
+        timeout_ms: Option<u64>,
+    },
+    /// Go back one page in the tab's history, waiting like `Use`.
+    Back {
+        tab: String,
+        timeout_ms: Option<u64>,
+    },
+    /// Go forward one page in the tab's history, waiting like `Use`.
+    Forward {
+        tab: String,
+        timeout_ms: Option<u64>,
+    },
+    /// Load the current page again, waiting like `Use`.
+    Reload {
+        tab: String,
+        timeout_ms: Option<u64>,
+    },
+    History {
+        tab: String,
+    },
+    /// Lists the tab's controls, only those activated with `method` (any
+    /// case) when it is given.
+    Controls {
+        tab: String,
+        method: Option<String>,
+    },
     Tabs,
     Shutdown,
 }
 
+/// A method call as sent on the wire: the command plus varlink call flags.
+///
+/// Serializes as `{"method": "Watch", "parameters": {...}, "more": true}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonCall {
+    #[serde(flatten)]
+    pub command: DaemonCommand,
+    /// The caller accepts multiple replies.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub more: bool,
+}
+
 /// Daemon error variants (varlink errors).
 ///
 /// Serializes as `{"error": "TabNotFound", "parameters": {"tab": "1"}}`.
@@ -111,9 +214,32 @@ pub enum DaemonCommand {
 pub enum DaemonError {
     TabNotFound { tab: String },
     PathNotFound { tab: String, path: String },
+    /// The page's hypermap has a malformed `#` key, so no path in it can be
+    /// looked up.
+    InvalidHypermap { tab: String, reason: String },
+    /// `Use` or `Preview` on something other than a control.
+    NotAControl { tab: String, path: String },
+    /// `Set`, or `Use` data, on a control, map or list rather than a value.
+    NotAValue { tab: String, path: String },
+    /// The page threw an exception while mechd was driving it.
+    ScriptError { tab: String, message: String },
     NameInUse { name: String },
     InvalidUrl { url: String, reason: String },
     PageError { message: String },
+    Timeout { tab: String, timeout_ms: u64 },
+    InvalidPattern { pattern: String, reason: String },
+    InvalidSelector { selector: String, reason: String },
+    /// A streaming method was called without `more`.
+    ExpectedMore,
+    /// `Back` or `Forward` past the end of the history; `direction` is
+    /// `back` or `forward`.
+    NoHistoryEntry { tab: String, direction: String },
+    /// mechd was built without the requested backend.
+    BackendUnavailable { backend: Backend },
+    /// Client-side only: the daemon socket could not be reached.
+    ConnectionFailed { reason: String },
+    /// Client-side only: the daemon sent a reply the client could not decode.
+    InvalidReply { reason: String },
 }
 
 impl DaemonError {
@@ -124,40 +250,375 @@ impl DaemonError {
             DaemonError::PathNotFound { tab, path } => {
                 format!("Path '{}' not found in tab '{}'", path, tab)
             }
+            DaemonError::InvalidHypermap { tab, reason } => {
+                format!("Tab '{}' has an invalid hypermap: {}", tab, reason)
+            }
+            DaemonError::NotAControl { tab, path } => {
+                format!("'{}' in tab '{}' is not a control", path, tab)
+            }
+            DaemonError::NotAValue { tab, path } => {
+                format!("'{}' in tab '{}' is not a value that can be set", path, tab)
+            }
+            DaemonError::ScriptError { tab, message } => {
+                format!("Script error in tab '{}': {}", tab, message)
+            }
             DaemonError::NameInUse { name } => format!("Tab name '{}' already in use", name),
             DaemonError::InvalidUrl { url, reason } => {
                 format!("Invalid URL '{}': {}", url, reason)
             }
             DaemonError::PageError { message } => message.trim_end().to_string(),
+            DaemonError::Timeout { tab, timeout_ms } => {
+                format!("Timed out after {}ms waiting for tab '{}'", timeout_ms, tab)
+            }
+            DaemonError::InvalidPattern { pattern, reason } => {
+                format!("Invalid pattern '{}': {}", pattern, reason)
+            }
+            DaemonError::InvalidSelector { selector, reason } => {
+                format!("Invalid selector '{}': {}", selector, reason)
+            }
+            DaemonError::NoHistoryEntry { tab, direction } => {
+                format!("Tab '{}' has no page to go {} to", tab, direction)
+            }
+            DaemonError::BackendUnavailable { backend } => {
+                format!("mechd was built without the {} backend", backend)
+            }
+            DaemonError::ExpectedMore => {
+                "This method streams replies; call it with more set".to_string()
+            }
+            DaemonError::ConnectionFailed { .. } => {
+                "Failed to connect to daemon. Is it running? Try: mech start".to_string()
+            }
+            DaemonError::InvalidReply { .. } => {
+                "Invalid response from daemon. Is mechd up to date?".to_string()
+            }
+        }
+    }
+}
+
+/// How far a tab has got loading its current resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadState {
+    Loading,
+    Complete,
+}
+
+/// What renders a tab's resources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The Servo browser engine, which runs the resource's scripts.
+    Servo,
+    /// Plain HTTP and JSON, without scripts: a hypermap only changes
+    /// through `Set` and `Use`.
+    Native,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Servo => "servo",
+            Backend::Native => "native",
+        })
+    }
+}
+
+/// A tab as reported by `Open`, `Fork` and `Tabs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TabInfo {
+    /// Assigned when the tab is opened and never reused while the daemon
+    /// runs, so closing other tabs does not change it.
+    pub id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub url: String,
+    pub state: LoadState,
+    pub backend: Backend,
+}
+
+/// A tab's session history as returned by `History`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryInfo {
+    pub entries: Vec<String>,
+    /// Index into `entries` of the page the tab is showing.
+    pub current: usize,
+}
+
+/// A node matched by `Select`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Match {
+    /// Slash-separated path from the root of the tab's hypermap; empty for
+    /// the root itself.
+    pub path: String,
+    /// The node, in the same lossless form as `Show` returns.
+    pub hypermap: Value,
+}
+
+/// A control as listed by `Controls`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlInfo {
+    /// Slash-separated path from the root of the tab's hypermap; empty for
+    /// the root itself.
+    pub path: String,
+    /// Upper-cased, as the request is made.
+    pub method: String,
+    /// The `href` resolved against the page's URL, or as declared if it
+    /// cannot be.
+    pub target: String,
+}
+
+impl ControlInfo {
+    /// Every control in `node`, a tab's hypermap loaded from `base`, keeping
+    /// only those activated with `method` if it is given.
+    pub fn list(node: &Node, base: &Url, method: Option<&str>) -> Vec<ControlInfo> {
+        node.controls()
+            .into_iter()
+            .filter(|(_, map)| {
+                method.is_none_or(|m| map.attributes.method().eq_ignore_ascii_case(m))
+            })
+            .map(|(path, map)| {
+                let href = map.attributes.href.as_deref().unwrap_or_default();
+                ControlInfo {
+                    path,
+                    method: map.attributes.method().to_ascii_uppercase(),
+                    target: base.join(href).map_or_else(|_| href.to_string(), String::from),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A request as returned by `Preview`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestInfo {
+    pub method: String,
+    /// Absolute, with the query string a GET control sends.
+    pub url: String,
+    /// The JSON body every method but GET sends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+impl From<Request> for RequestInfo {
+    fn from(request: Request) -> Self {
+        RequestInfo {
+            method: request.method,
+            url: request.url.into(),
+            body: request.body,
+        }
+    }
+}
+
+/// What `Wait` waits for at a path.
+///
+/// Serializes as `{"kind": "equals", "value": "filled"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum WaitCondition {
+    /// The path is present.
+    Exists,
+    /// The value's text equals `value`.
+    Equals { value: String },
+    /// The value differs from the one seen when the wait began.
+    Changes,
+    /// The value's text matches the regular expression `pattern`.
+    Matches { pattern: String },
+}
+
+/// Evaluates a `WaitCondition` against successive values of a path.
+pub struct WaitCheck {
+    condition: WaitCondition,
+    regex: Option<Regex>,
+    /// The first value observed, kept for `WaitCondition::Changes`.
+    initial: Option<Option<Value>>,
+}
+
+impl WaitCheck {
+    pub fn new(condition: WaitCondition) -> Result<Self, DaemonError> {
+        let regex = match &condition {
+            WaitCondition::Matches { pattern } => {
+                Some(Regex::new(pattern).map_err(|e| DaemonError::InvalidPattern {
+                    pattern: pattern.clone(),
+                    reason: e.to_string(),
+                })?)
+            }
+            _ => None,
+        };
+        Ok(WaitCheck {
+            condition,
+            regex,
+            initial: None,
+        })
+    }
+
+    /// Record the current value at the path (`None` if it is absent) and
+    /// report whether the condition now holds.
+    pub fn observe(&mut self, current: Option<&Value>) -> bool {
+        let initial = self.initial.get_or_insert_with(|| current.cloned());
+        match &self.condition {
+            WaitCondition::Exists => current.is_some(),
+            WaitCondition::Equals { value } => current.is_some_and(|v| value_text(v) == *value),
+            WaitCondition::Changes => initial.as_ref() != current,
+            WaitCondition::Matches { .. } => match (&self.regex, current) {
+                (Some(regex), Some(v)) => regex.is_match(&value_text(v)),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// The text a wait condition compares against, `Finder` searches, and the
+/// tree output shows: strings without quotes, a number tag as the number it
+/// stands for, and everything else as compact JSON.
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => number_tag(other).map_or_else(|| other.to_string(), str::to_string),
+    }
+}
+
+/// Searches the keys and values of a hypermap, as `mech find` does.
+pub struct Finder {
+    regex: Regex,
+}
+
+impl Finder {
+    /// Look for `pattern` as plain text, or as a regular expression if
+    /// `regex` is set, optionally ignoring case.
+    pub fn new(pattern: &str, regex: bool, ignore_case: bool) -> Result<Self, DaemonError> {
+        let source = if regex {
+            pattern.to_string()
+        } else {
+            regex::escape(pattern)
+        };
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| DaemonError::InvalidPattern {
+                pattern: pattern.to_string(),
+                reason: e.to_string(),
+            })?;
+        Ok(Finder { regex })
+    }
+
+    /// Every node at or below `node` whose key contains the pattern, or that
+    /// is a value containing it, with its full path, in document order.
+    /// `prefix` is the path `node` was taken from; its own key is not
+    /// searched, and neither are list indices or `#` attributes.
+    pub fn find<'a>(&self, node: &'a Node, prefix: &str) -> Vec<(String, &'a Node)> {
+        let mut found = Vec::new();
+        self.find_recursive(node, prefix.to_string(), None, &mut found);
+        found
+    }
+
+    fn find_recursive<'a>(
+        &self,
+        node: &'a Node,
+        path: String,
+        key: Option<&str>,
+        found: &mut Vec<(String, &'a Node)>,
+    ) {
+        let key_matches = key.is_some_and(|key| self.regex.is_match(key));
+        match node {
+            Node::Map(map) => {
+                if key_matches {
+                    found.push((path.clone(), node));
+                }
+                for (key, child) in map.iter() {
+                    self.find_recursive(child, join_path(&path, key), Some(key), found);
+                }
+            }
+            Node::List(list) => {
+                if key_matches {
+                    found.push((path.clone(), node));
+                }
+                for (i, child) in list.iter().enumerate() {
+                    self.find_recursive(child, join_path(&path, &i.to_string()), None, found);
+                }
+            }
+            Node::Value(value) => {
+                if key_matches || self.regex.is_match(&value_text(&value.value)) {
+                    found.push((path, node));
+                }
+            }
         }
     }
 }
 
 /// Successful response parameters.
+///
+/// Fields other than `message` are only present for the methods that return
+/// them, so unit returns still serialize as `{}`.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DaemonOk {
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tab: Option<TabInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tabs: Option<Vec<TabInfo>>,
+    /// The hypermap (or the subtree at the requested path) returned by `Show`,
+    /// or the value that satisfied a `Wait`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hypermap: Option<Value>,
+    /// The URL a tab ended up at after `Use`, `Goto`, `Back`, `Forward` or
+    /// `Reload`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<HistoryInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matches: Option<Vec<Match>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controls: Option<Vec<ControlInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestInfo>,
 }
 
 /// Daemon reply (varlink reply).
 ///
 /// On success: `{"parameters": {"message": "..."}}` (or `{"parameters": {}}` for unit returns).
 /// On error: `{"error": "TabNotFound", "parameters": {"tab": "1"}}`.
+/// Streamed replies add `"continues": true` to all but the last.
 ///
 /// `Err` is tried first when deserializing untagged: it requires a top-level
 /// `error` field, so success replies fall through to the `Ok` variant.
+// Replies are serialized as soon as they are built, so the size of the `Ok`
+// variant costs nothing worth boxing it for.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum DaemonReply {
     Err(DaemonError),
-    Ok { parameters: DaemonOk },
+    Ok {
+        parameters: DaemonOk,
+        /// More replies follow (varlink `continues`), as streamed by `Watch`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        continues: bool,
+    },
 }
 
 impl DaemonReply {
+    /// Mark a successful reply as one of a stream, with more to follow.
+    pub fn continuing(self) -> Self {
+        match self {
+            DaemonReply::Ok { parameters, .. } => DaemonReply::Ok {
+                parameters,
+                continues: true,
+            },
+            err => err,
+        }
+    }
+
+    /// Whether more replies follow this one on the same connection.
+    pub fn continues(&self) -> bool {
+        matches!(self, DaemonReply::Ok { continues: true, .. })
+    }
+
     pub fn ok() -> Self {
         DaemonReply::Ok {
             parameters: DaemonOk::default(),
+            continues: false,
         }
     }
 
@@ -165,119 +626,458 @@ impl DaemonReply {
         DaemonReply::Ok {
             parameters: DaemonOk {
                 message: message.into(),
+                ..DaemonOk::default()
             },
+            continues: false,
         }
     }
+
+    pub fn ok_tab(message: impl Into<String>, tab: TabInfo) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                message: message.into(),
+                tab: Some(tab),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_tabs(tabs: Vec<TabInfo>) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                tabs: Some(tabs),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_hypermap(hypermap: Value) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                hypermap: Some(hypermap),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    /// A hypermap with the URL of the page it came from, which its relative
+    /// `href`s and `scripts` resolve against.
+    pub fn ok_snapshot(hypermap: Value, url: impl Into<String>) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                hypermap: Some(hypermap),
+                url: Some(url.into()),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_history(history: HistoryInfo) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                history: Some(history),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_matches(matches: Vec<Match>) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                matches: Some(matches),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_controls(controls: Vec<ControlInfo>) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                controls: Some(controls),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_request(request: RequestInfo) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                request: Some(request),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+
+    pub fn ok_url(url: impl Into<String>) -> Self {
+        DaemonReply::Ok {
+            parameters: DaemonOk {
+                url: Some(url.into()),
+                ..DaemonOk::default()
+            },
+            continues: false,
+        }
+    }
+}
+
+/// Render a tab's history as printed by `mech history`, oldest first, with
+/// the current page marked `*`.
+pub fn format_history(history: &HistoryInfo) -> String {
+    let mut output = String::new();
+    for (i, url) in history.entries.iter().enumerate() {
+        let marker = if i == history.current { '*' } else { ' ' };
+        writeln!(output, "{} {}  {}", marker, i + 1, url).unwrap();
+    }
+    output
+}
+
+/// Render a control list as printed by `mech controls`, one
+/// `method  path  target` line per control with the columns aligned.
+pub fn format_controls(controls: &[ControlInfo]) -> String {
+    if controls.is_empty() {
+        return "No controls\n".to_string();
+    }
+    let path = |control: &ControlInfo| {
+        if control.path.is_empty() {
+            "(root)".to_string()
+        } else {
+            control.path.clone()
+        }
+    };
+    let method_width = controls.iter().map(|c| c.method.len()).max().unwrap_or(0);
+    let path_width = controls.iter().map(|c| path(c).len()).max().unwrap_or(0);
+    let mut output = String::new();
+    for control in controls {
+        writeln!(
+            output,
+            "{:method_width$}  {:path_width$}  {}",
+            control.method,
+            path(control),
+            control.target
+        )
+        .unwrap();
+    }
+    output
+}
+
+/// Render a request as printed by `mech use --dry-run`: the method and URL,
+/// then the body as pretty-printed JSON if there is one.
+pub fn format_request(request: &RequestInfo) -> String {
+    let mut output = format!("{} {}\n", request.method, request.url);
+    if let Some(body) = &request.body {
+        output.push_str(&serde_json::to_string_pretty(body).expect("JSON values always serialize"));
+        output.push('\n');
+    }
+    output
+}
+
+/// Render a tab list as printed by `mech tabs`.
+pub fn format_tabs(tabs: &[TabInfo]) -> String {
+    if tabs.is_empty() {
+        return "No open tabs\n".to_string();
+    }
+    let mut output = String::new();
+    for tab in tabs {
+        let name_part = tab
+            .name
+            .as_ref()
+            .map(|n| format!(" ({})", n))
+            .unwrap_or_default();
+        // Servo tabs are the usual kind; native ones do not run scripts
+        let mut markers = Vec::new();
+        if tab.backend == Backend::Native {
+            markers.push("[native]");
+        }
+        if tab.state == LoadState::Loading {
+            markers.push("[loading]");
+        }
+        let markers_part = if markers.is_empty() {
+            String::new()
+        } else {
+            format!("  {}", markers.join(" "))
+        };
+        writeln!(output, "{}{}  {}{}", tab.id, name_part, tab.url, markers_part).unwrap();
+    }
+    output
 }
 
 #[cfg(test)]
-pub fn format_hypermap(value: &Value, indent: usize) -> String {
-    format_hypermap_styled(value, indent, false)
+pub fn format_hypermap(node: &Node, indent: usize) -> String {
+    format_hypermap_styled(node, indent, false)
 }
 
-pub fn format_hypermap_styled(value: &Value, indent: usize, use_color: bool) -> String {
+pub fn format_hypermap_styled(node: &Node, indent: usize, use_color: bool) -> String {
     let mut output = String::new();
-    format_hypermap_recursive(value, indent, &mut output, use_color);
+    format_hypermap_recursive(node, indent, &mut output, use_color);
     output
 }
 
-fn format_hypermap_recursive(value: &Value, indent: usize, output: &mut String, use_color: bool) {
+fn format_hypermap_recursive(node: &Node, indent: usize, output: &mut String, use_color: bool) {
+    match node {
+        Node::Map(map) => {
+            for (key, child) in map.iter() {
+                format_hypermap_entry(key, child, indent, output, use_color);
+            }
+        }
+        Node::List(list) => {
+            for (i, child) in list.iter().enumerate() {
+                format_hypermap_entry(&format!("[{}]", i), child, indent, output, use_color);
+            }
+        }
+        // For scalar values at root level
+        Node::Value(value) => {
+            writeln!(output, "{}{}", "  ".repeat(indent), value_text(&value.value)).unwrap();
+        }
+    }
+}
+
+/// Write one child of a map or list: `key: value` for values, or the key
+/// followed by the child's own entries.
+fn format_hypermap_entry(
+    key: &str,
+    node: &Node,
+    indent: usize,
+    output: &mut String,
+    use_color: bool,
+) {
     let indent_str = "  ".repeat(indent);
+    let formatted_key = entry_label(key, node, use_color);
 
-    match value {
-        Value::Object(map) => {
-            for (key, val) in map {
-                // Skip the "#" metadata key
-                if key == "#" {
-                    continue;
-                }
+    match node {
+        Node::Value(value) => {
+            writeln!(output, "{}{}: {}", indent_str, formatted_key, value_text(&value.value))
+                .unwrap();
+        }
+        Node::Map(_) | Node::List(_) => {
+            writeln!(output, "{}{}", indent_str, formatted_key).unwrap();
+            if has_children(node) {
+                format_hypermap_recursive(node, indent + 1, output, use_color);
+            }
+        }
+    }
+}
 
-                // Check if this key has a control marker (has "#" child with type: "control")
-                let is_control = if let Value::Object(obj) = val {
-                    obj.get("#")
-                        .and_then(|v| v.get("type"))
-                        .and_then(|v| v.as_str())
-                        == Some("control")
-                } else {
-                    false
-                };
-
-                // Check if object has non-# children
-                let has_children = if let Value::Object(obj) = val {
-                    obj.iter().any(|(k, _)| k != "#")
-                } else {
-                    matches!(val, Value::Array(_))
-                };
-
-                // Build suffix: @ for control, / for has children
-                let suffix = match (is_control, has_children) {
-                    (true, true) => "@/",
-                    (true, false) => "@",
-                    (false, true) => "/",
-                    (false, false) => "",
-                };
-
-                // Format the key with optional bold for controls
-                let formatted_key = if is_control && use_color {
-                    format!("\x1b[1m{}{}\x1b[0m", key, suffix)
-                } else {
-                    format!("{}{}", key, suffix)
-                };
-
-                // Format the value
-                match val {
-                    Value::Object(_) | Value::Array(_) => {
-                        writeln!(output, "{}{}", indent_str, formatted_key).unwrap();
-                        if has_children {
-                            format_hypermap_recursive(val, indent + 1, output, use_color);
-                        }
-                    }
-                    // Leaf nodes with values
-                    Value::String(s) => {
-                        writeln!(output, "{}{}: {}", indent_str, formatted_key, s).unwrap();
-                    }
-                    Value::Number(n) => {
-                        writeln!(output, "{}{}: {}", indent_str, formatted_key, n).unwrap();
-                    }
-                    Value::Bool(b) => {
-                        writeln!(output, "{}{}: {}", indent_str, formatted_key, b).unwrap();
-                    }
-                    Value::Null => {
-                        writeln!(output, "{}{}: null", indent_str, formatted_key).unwrap();
+fn has_children(node: &Node) -> bool {
+    match node {
+        Node::Map(map) => !map.is_empty(),
+        Node::List(_) => true,
+        Node::Value(_) => false,
+    }
+}
+
+/// The key of an entry as the tree shows it: followed by `@` for a control
+/// and `/` if it has children, and bold for a control when `use_color`.
+fn entry_label(key: &str, node: &Node, use_color: bool) -> String {
+    let is_control = node.is_control();
+
+    // Build suffix: @ for control, / for has children
+    let suffix = match (is_control, has_children(node)) {
+        (true, true) => "@/",
+        (true, false) => "@",
+        (false, true) => "/",
+        (false, false) => "",
+    };
+
+    // Format the key with optional bold for controls
+    if is_control && use_color {
+        format!("\x1b[1m{}{}\x1b[0m", key, suffix)
+    } else {
+        format!("{}{}", key, suffix)
+    }
+}
+
+/// Render the `#` attributes of every map at or below `path`, as printed by
+/// `mech inspect`: a control's method, its target resolved against `base`
+/// and its fields with their current values, and any scripts a map declares.
+pub fn format_inspect(node: &Node, path: &str, base: &Url) -> String {
+    let mut blocks = Vec::new();
+    format_inspect_recursive(node, path, base, &mut blocks);
+    if blocks.is_empty() {
+        let place = if path.is_empty() { "the root" } else { path };
+        return format!("No controls or scripts at {}\n", place);
+    }
+    blocks.join("\n")
+}
+
+fn format_inspect_recursive(node: &Node, path: &str, base: &Url, blocks: &mut Vec<String>) {
+    let child_path = |key: &str| join_path(path, key);
+    let resolve = |url: &str| match base.join(url) {
+        Ok(resolved) => resolved.to_string(),
+        Err(e) => format!("{} (invalid: {})", url, e),
+    };
+
+    match node {
+        Node::Map(map) => {
+            if !map.attributes.is_empty() {
+                let mut block = String::new();
+                let name = if path.is_empty() { "(root)" } else { path };
+                let marker = if map.is_control() { "@" } else { "" };
+                writeln!(block, "{}{}", name, marker).unwrap();
+                if let Some(href) = &map.attributes.href {
+                    writeln!(block, "  method: {}", map.attributes.method()).unwrap();
+                    writeln!(block, "  target: {}", resolve(href)).unwrap();
+                }
+                if let Some(scripts) = &map.attributes.scripts {
+                    writeln!(block, "  scripts:").unwrap();
+                    for script in scripts {
+                        writeln!(block, "    {}", resolve(script)).unwrap();
                     }
                 }
+                if map.is_control() && !map.is_empty() {
+                    writeln!(block, "  fields:").unwrap();
+                    block.push_str(&format_hypermap_styled(node, 2, false));
+                }
+                blocks.push(block);
+            }
+            for (key, child) in map.iter() {
+                format_inspect_recursive(child, &child_path(key), base, blocks);
             }
         }
-        Value::Array(arr) => {
-            for (i, val) in arr.iter().enumerate() {
-                let has_children = matches!(val, Value::Object(_) | Value::Array(_));
-                let suffix = if has_children { "/" } else { "" };
+        Node::List(list) => {
+            for (i, child) in list.iter().enumerate() {
+                format_inspect_recursive(child, &child_path(&i.to_string()), base, blocks);
+            }
+        }
+        Node::Value(_) => {}
+    }
+}
 
-                match val {
-                    Value::Object(_) | Value::Array(_) => {
-                        writeln!(output, "{}[{}]{}", indent_str, i, suffix).unwrap();
-                        format_hypermap_recursive(val, indent + 1, output, use_color);
-                    }
-                    Value::String(s) => writeln!(output, "{}[{}]: {}", indent_str, i, s).unwrap(),
-                    Value::Number(n) => writeln!(output, "{}[{}]: {}", indent_str, i, n).unwrap(),
-                    Value::Bool(b) => writeln!(output, "{}[{}]: {}", indent_str, i, b).unwrap(),
-                    Value::Null => writeln!(output, "{}[{}]: null", indent_str, i).unwrap(),
-                }
+/// Render a hypermap as pretty-printed JSON, exactly as the daemon sent it.
+pub fn format_hypermap_json(node: &Node) -> String {
+    pretty_json(&node.to_json())
+}
+
+/// Render a hypermap as a YAML document, exactly as the daemon sent it.
+pub fn format_hypermap_yaml(node: &Node) -> String {
+    serde_yaml::to_string(&node.to_json()).expect("JSON values always serialize")
+}
+
+/// Render a hypermap as pretty-printed JSON in the spec's lossy `toView()`
+/// form, where a control's `#` only says that it is one.
+pub fn format_hypermap_view(node: &Node) -> String {
+    pretty_json(&node.to_view())
+}
+
+fn pretty_json(value: &Value) -> String {
+    let mut output = serde_json::to_string_pretty(value).expect("JSON values always serialize");
+    output.push('\n');
+    output
+}
+
+/// Render a hypermap as one `path<TAB>value` line per leaf.
+///
+/// Values are JSON-encoded so that every entry stays on a single line, and
+/// strings can be told apart from numbers. Controls get their own line with
+/// the bare value `@`, which no JSON value can collide with. Empty containers
+/// are printed as `{}` or `[]` so they are not lost. `prefix` is the path the
+/// node was taken from, so printed paths are always absolute.
+pub fn format_hypermap_flat(node: &Node, prefix: &str) -> String {
+    let mut output = String::new();
+    format_flat_recursive(node, prefix, &mut output);
+    output
+}
+
+fn format_flat_recursive(node: &Node, path: &str, output: &mut String) {
+    let child_path = |key: &str| join_path(path, key);
+
+    match node {
+        Node::Map(map) => {
+            if map.is_control() {
+                writeln!(output, "{}\t@", path).unwrap();
+            } else if map.is_empty() {
+                writeln!(output, "{}\t{{}}", path).unwrap();
+            }
+            for (key, child) in map.iter() {
+                format_flat_recursive(child, &child_path(key), output);
             }
         }
-        _ => {
-            // For scalar values at root level
-            match value {
-                Value::String(s) => writeln!(output, "{}{}", indent_str, s).unwrap(),
-                Value::Number(n) => writeln!(output, "{}{}", indent_str, n).unwrap(),
-                Value::Bool(b) => writeln!(output, "{}{}", indent_str, b).unwrap(),
-                Value::Null => writeln!(output, "{}null", indent_str).unwrap(),
-                _ => {}
+        Node::List(list) => {
+            if list.is_empty() {
+                writeln!(output, "{}\t[]", path).unwrap();
+            }
+            for (i, child) in list.iter().enumerate() {
+                format_flat_recursive(child, &child_path(&i.to_string()), output);
             }
         }
+        Node::Value(value) => match number_tag(&value.value) {
+            Some(name) => writeln!(output, "{}\t{}", path, name).unwrap(),
+            None => writeln!(output, "{}\t{}", path, value.value).unwrap(),
+        },
     }
 }
 
+/// Render the nodes a selector matched as printed by `mech show`: each one
+/// under its path the way the tree shows a child under its key, with the
+/// root as `(root)`.
+pub fn format_matches_styled(matches: &[(String, Node)], use_color: bool) -> String {
+    if matches.is_empty() {
+        return "No matches\n".to_string();
+    }
+    let mut output = String::new();
+    for (path, node) in matches {
+        let path = if path.is_empty() { "(root)" } else { path };
+        format_hypermap_entry(path, node, 0, &mut output, use_color);
+    }
+    output
+}
+
+/// Render what `Finder` found as printed by `mech find`: one line per node
+/// with its full path, marked as the tree marks keys, and a value's text.
+pub fn format_found(found: &[(String, &Node)], use_color: bool) -> String {
+    if found.is_empty() {
+        return "No matches\n".to_string();
+    }
+    let mut output = String::new();
+    for (path, node) in found {
+        let path = if path.is_empty() { "(root)" } else { path };
+        let label = entry_label(path, node, use_color);
+        match node {
+            Node::Value(value) => writeln!(output, "{}: {}", label, value_text(&value.value)),
+            _ => writeln!(output, "{}", label),
+        }
+        .unwrap();
+    }
+    output
+}
+
+/// The matches as one object from path to node, each converted by `form`.
+fn matches_object(matches: &[(String, Node)], form: fn(&Node) -> Value) -> Value {
+    let object = matches.iter().map(|(path, node)| (path.clone(), form(node)));
+    Value::Object(object.collect())
+}
+
+/// Render the nodes a selector matched as a pretty-printed JSON object,
+/// keyed by path.
+pub fn format_matches_json(matches: &[(String, Node)]) -> String {
+    pretty_json(&matches_object(matches, Node::to_json))
+}
+
+/// Render the nodes a selector matched as a YAML mapping, keyed by path.
+pub fn format_matches_yaml(matches: &[(String, Node)]) -> String {
+    serde_yaml::to_string(&matches_object(matches, Node::to_json))
+        .expect("JSON values always serialize")
+}
+
+/// Render the nodes a selector matched as `format_matches_json` does, but
+/// each in `toView()` form.
+pub fn format_matches_view(matches: &[(String, Node)]) -> String {
+    pretty_json(&matches_object(matches, Node::to_view))
+}
+
+/// Render the nodes a selector matched as `path<TAB>value` lines, as
+/// `format_hypermap_flat` renders each one.
+pub fn format_matches_flat(matches: &[(String, Node)]) -> String {
+    let flat = matches.iter().map(|(path, node)| format_hypermap_flat(node, path));
+    flat.collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,6 +1086,10 @@ mod tests {
 
     // -- Roundtrip serialization tests ----------------------------------------
 
+    fn node(value: Value) -> Node {
+        Node::from_value(value).unwrap()
+    }
+
     fn roundtrip(cmd: &DaemonCommand) -> DaemonCommand {
         let json = serde_json::to_vec(cmd).unwrap();
         serde_json::from_slice(&json).unwrap()
@@ -296,10 +1100,12 @@ mod tests {
         let cmd = DaemonCommand::Open {
             url: "https://example.com".into(),
             name: None,
+            backend: None,
+            timeout_ms: None,
         };
         assert!(matches!(
             roundtrip(&cmd),
-            DaemonCommand::Open { ref url, name: None } if url == "https://example.com"
+            DaemonCommand::Open { ref url, name: None, backend: None, timeout_ms: None } if url == "https://example.com"
         ));
     }
 
@@ -308,10 +1114,12 @@ mod tests {
         let cmd = DaemonCommand::Open {
             url: "https://example.com".into(),
             name: Some("myapp".into()),
+            backend: Some(Backend::Native),
+            timeout_ms: Some(5000),
         };
         assert!(matches!(
             roundtrip(&cmd),
-            DaemonCommand::Open { ref url, ref name }
+            DaemonCommand::Open { ref url, ref name, backend: Some(Backend::Native), timeout_ms: Some(5000) }
                 if url == "https://example.com" && name.as_deref() == Some("myapp")
         ));
     }
@@ -321,11 +1129,10 @@ mod tests {
         let cmd = DaemonCommand::Show {
             tab: "1".into(),
             path: Some("nav/home".into()),
-            color: true,
         };
         assert!(matches!(
             roundtrip(&cmd),
-            DaemonCommand::Show { ref tab, ref path, color: true }
+            DaemonCommand::Show { ref tab, ref path }
                 if tab == "1" && path.as_deref() == Some("nav/home")
         ));
     }
@@ -346,6 +1153,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn set_values_keep_their_type() {
+        let cmd = DaemonCommand::Set {
+            tab: "1".into(),
+            path: "market/ibm/quantity".into(),
+            value: json!(100),
+        };
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert!(json.contains(r#""value":100"#));
+        assert!(matches!(roundtrip(&cmd), DaemonCommand::Set { value, .. } if value == 100));
+    }
+
     #[test]
     fn roundtrip_use_with_data() {
         let mut data = HashMap::new();
@@ -354,11 +1173,13 @@ mod tests {
             tab: "1".into(),
             path: "submit".into(),
             data,
+            timeout_ms: Some(0),
         };
-        if let DaemonCommand::Use { tab, path, data } = roundtrip(&cmd) {
+        if let DaemonCommand::Use { tab, path, data, timeout_ms } = roundtrip(&cmd) {
             assert_eq!(tab, "1");
             assert_eq!(path, "submit");
-            assert_eq!(data.get("quantity"), Some(&"5".to_string()));
+            assert_eq!(timeout_ms, Some(0));
+            assert_eq!(data.get("quantity"), Some(&Value::from("5")));
             assert_eq!(data.len(), 1);
         } else {
             panic!("Expected Use");
@@ -374,6 +1195,7 @@ mod tests {
             tab: "1".into(),
             path: "submit".into(),
             data,
+            timeout_ms: None,
         };
         let val: Value = serde_json::to_value(&cmd).unwrap();
         let params = &val["parameters"];
@@ -387,10 +1209,11 @@ mod tests {
         let cmd = DaemonCommand::Fork {
             tab: "stocks".into(),
             name: Some("stocks2".into()),
+            timeout_ms: None,
         };
         assert!(matches!(
             roundtrip(&cmd),
-            DaemonCommand::Fork { ref tab, ref name }
+            DaemonCommand::Fork { ref tab, ref name, timeout_ms: None }
                 if tab == "stocks" && name.as_deref() == Some("stocks2")
         ));
     }
@@ -415,6 +1238,36 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn roundtrip_goto() {
+        let cmd = DaemonCommand::Goto {
+            tab: "main".into(),
+            url: "../orders/".into(),
+            timeout_ms: None,
+        };
+        if let DaemonCommand::Goto { tab, url, timeout_ms } = roundtrip(&cmd) {
+            assert_eq!(tab, "main");
+            assert_eq!(url, "../orders/");
+            assert_eq!(timeout_ms, None);
+        } else {
+            panic!("Expected Goto");
+        }
+    }
+
+    #[test]
+    fn roundtrip_history_navigation() {
+        let cmd = DaemonCommand::Back {
+            tab: "1".into(),
+            timeout_ms: Some(1000),
+        };
+        assert!(matches!(
+            roundtrip(&cmd),
+            DaemonCommand::Back { ref tab, timeout_ms: Some(1000) } if tab == "1"
+        ));
+        let val = serde_json::to_value(DaemonCommand::History { tab: "1".into() }).unwrap();
+        assert_eq!(val, json!({"method": "History", "parameters": {"tab": "1"}}));
+    }
+
     #[test]
     fn roundtrip_tabs() {
         assert!(matches!(roundtrip(&DaemonCommand::Tabs), DaemonCommand::Tabs));
@@ -433,12 +1286,22 @@ mod tests {
         let cmd = DaemonCommand::Open {
             url: "https://example.com".into(),
             name: None,
+            backend: None,
+            timeout_ms: None,
         };
         let val: Value = serde_json::to_value(&cmd).unwrap();
         assert_eq!(val["method"], "Open");
         assert!(val["parameters"].is_object());
     }
 
+    #[test]
+    fn open_without_timeout_parses() {
+        let cmd: DaemonCommand =
+            serde_json::from_str(r#"{"method": "Open", "parameters": {"url": "https://example.com"}}"#)
+                .unwrap();
+        assert!(matches!(cmd, DaemonCommand::Open { backend: None, timeout_ms: None, .. }));
+    }
+
     #[test]
     fn json_shape_unit_variant() {
         let val: Value = serde_json::to_value(&DaemonCommand::Tabs).unwrap();
@@ -483,7 +1346,7 @@ mod tests {
         let json = serde_json::to_vec(&reply).unwrap();
         let parsed: DaemonReply = serde_json::from_slice(&json).unwrap();
         match parsed {
-            DaemonReply::Ok { parameters } => assert_eq!(parameters.message, "Opened tab 1"),
+            DaemonReply::Ok { parameters, .. } => assert_eq!(parameters.message, "Opened tab 1"),
             DaemonReply::Err(_) => panic!("Expected Ok"),
         }
     }
@@ -495,6 +1358,60 @@ mod tests {
         assert_eq!(val["parameters"]["message"], "Opened tab 1");
     }
 
+    #[test]
+    fn reply_ok_tab_wire_shape() {
+        let reply = DaemonReply::ok_tab(
+            "Opened tab 2",
+            TabInfo {
+                id: 2,
+                name: None,
+                url: "https://example.com/".into(),
+                state: LoadState::Loading,
+                backend: Backend::Native,
+            },
+        );
+        let val: Value = serde_json::to_value(&reply).unwrap();
+        assert_eq!(val["parameters"]["tab"]["id"], 2);
+        assert_eq!(val["parameters"]["tab"]["url"], "https://example.com/");
+        assert_eq!(val["parameters"]["tab"]["state"], "loading");
+        assert_eq!(val["parameters"]["tab"]["backend"], "native");
+        // Unnamed tabs omit the name rather than sending null
+        assert!(val["parameters"]["tab"].get("name").is_none());
+    }
+
+    #[test]
+    fn reply_ok_hypermap_roundtrip() {
+        let reply = DaemonReply::ok_hypermap(json!({"nav": {"home": {"#": {"type": "control"}}}}));
+        let json = serde_json::to_vec(&reply).unwrap();
+        match serde_json::from_slice::<DaemonReply>(&json).unwrap() {
+            DaemonReply::Ok { parameters, .. } => {
+                assert_eq!(
+                    parameters.hypermap.unwrap()["nav"]["home"]["#"]["type"],
+                    "control"
+                );
+                assert!(parameters.message.is_empty());
+            }
+            DaemonReply::Err(_) => panic!("Expected Ok"),
+        }
+    }
+
+    #[test]
+    fn reply_ok_tabs_wire_shape() {
+        let reply = DaemonReply::ok_tabs(vec![TabInfo {
+            id: 1,
+            name: Some("stocks".into()),
+            url: "https://example.com/stocks/".into(),
+            state: LoadState::Complete,
+            backend: Backend::Servo,
+        }]);
+        let val: Value = serde_json::to_value(&reply).unwrap();
+        let tabs = val["parameters"]["tabs"].as_array().unwrap();
+        assert_eq!(tabs.len(), 1);
+        assert_eq!(tabs[0]["id"], 1);
+        assert_eq!(tabs[0]["name"], "stocks");
+        assert_eq!(tabs[0]["state"], "complete");
+    }
+
     #[test]
     fn reply_ok_empty_omits_message() {
         let val: Value = serde_json::to_value(DaemonReply::ok()).unwrap();
@@ -537,11 +1454,46 @@ mod tests {
         assert!(matches!(parsed, DaemonReply::Ok { .. }));
     }
 
+    #[test]
+    fn call_carries_more_flag() {
+        let call = DaemonCall {
+            command: DaemonCommand::Watch {
+                tab: "1".into(),
+                path: None,
+            },
+            more: true,
+        };
+        let val = serde_json::to_value(&call).unwrap();
+        assert_eq!(val["method"], "Watch");
+        assert_eq!(val["more"], true);
+
+        // Plain calls stay byte-for-byte what older daemons expect
+        let call = DaemonCall {
+            command: DaemonCommand::Tabs,
+            more: false,
+        };
+        assert_eq!(serde_json::to_value(&call).unwrap(), json!({"method": "Tabs"}));
+        let parsed: DaemonCall = serde_json::from_str(r#"{"method": "Tabs"}"#).unwrap();
+        assert!(!parsed.more);
+    }
+
+    #[test]
+    fn reply_continues_wire_shape() {
+        let reply = DaemonReply::ok_hypermap(json!(42)).continuing();
+        assert!(reply.continues());
+        let val = serde_json::to_value(&reply).unwrap();
+        assert_eq!(val, json!({"parameters": {"hypermap": 42}, "continues": true}));
+
+        let val = serde_json::to_value(DaemonReply::ok_hypermap(json!(42))).unwrap();
+        assert!(val.get("continues").is_none());
+        assert!(!DaemonReply::Err(DaemonError::ExpectedMore).continuing().continues());
+    }
+
     #[test]
     fn reply_ok_unit_parses_from_empty_parameters() {
         let parsed: DaemonReply = serde_json::from_str(r#"{"parameters": {}}"#).unwrap();
         match parsed {
-            DaemonReply::Ok { parameters } => assert_eq!(parameters.message, ""),
+            DaemonReply::Ok { parameters, .. } => assert_eq!(parameters.message, ""),
             DaemonReply::Err(_) => panic!("Expected Ok"),
         }
     }
@@ -564,41 +1516,514 @@ mod tests {
             DaemonError::NameInUse { name: "stocks".into() }.user_message(),
             "Tab name 'stocks' already in use"
         );
+        assert_eq!(
+            DaemonError::Timeout {
+                tab: "1".into(),
+                timeout_ms: 500
+            }
+            .user_message(),
+            "Timed out after 500ms waiting for tab '1'"
+        );
+        assert_eq!(
+            DaemonError::NoHistoryEntry {
+                tab: "1".into(),
+                direction: "back".into()
+            }
+            .user_message(),
+            "Tab '1' has no page to go back to"
+        );
+        assert_eq!(
+            DaemonError::NotAControl {
+                tab: "1".into(),
+                path: "market/acrn/price".into()
+            }
+            .user_message(),
+            "'market/acrn/price' in tab '1' is not a control"
+        );
+        assert_eq!(
+            DaemonError::ScriptError {
+                tab: "1".into(),
+                message: "TypeError: node is undefined".into()
+            }
+            .user_message(),
+            "Script error in tab '1': TypeError: node is undefined"
+        );
+    }
+
+    #[test]
+    fn invalid_selector_message() {
+        let err = DaemonError::InvalidSelector {
+            selector: "items[0".into(),
+            reason: "'[' without a matching ']'".into(),
+        };
+        assert_eq!(
+            err.user_message(),
+            "Invalid selector 'items[0': '[' without a matching ']'"
+        );
+    }
+
+    #[test]
+    fn new_errors_keep_the_wire_shape() {
+        let err = DaemonError::NotAValue {
+            tab: "1".into(),
+            path: "buy".into(),
+        };
+        let json = serde_json::to_value(DaemonReply::Err(err)).unwrap();
+        assert_eq!(
+            json,
+            json!({"error": "NotAValue", "parameters": {"tab": "1", "path": "buy"}})
+        );
+    }
+
+    // -- Wait condition tests -------------------------------------------------
+
+    #[test]
+    fn wait_condition_json_shape() {
+        let cond = WaitCondition::Equals {
+            value: "filled".into(),
+        };
+        let val = serde_json::to_value(&cond).unwrap();
+        assert_eq!(val, json!({"kind": "equals", "value": "filled"}));
+        let parsed: WaitCondition = serde_json::from_value(json!({"kind": "exists"})).unwrap();
+        assert_eq!(parsed, WaitCondition::Exists);
+    }
+
+    #[test]
+    fn wait_exists() {
+        let mut check = WaitCheck::new(WaitCondition::Exists).unwrap();
+        assert!(!check.observe(None));
+        assert!(check.observe(Some(&json!(null))));
+    }
+
+    #[test]
+    fn wait_equals_compares_text() {
+        let mut check = WaitCheck::new(WaitCondition::Equals { value: "42".into() }).unwrap();
+        assert!(!check.observe(Some(&json!(41))));
+        assert!(check.observe(Some(&json!(42))));
+        assert!(check.observe(Some(&json!("42"))));
+
+        let mut check = WaitCheck::new(WaitCondition::Equals {
+            value: "filled".into(),
+        })
+        .unwrap();
+        assert!(!check.observe(None));
+        assert!(check.observe(Some(&json!("filled"))));
+    }
+
+    #[test]
+    fn wait_changes_from_first_observation() {
+        let mut check = WaitCheck::new(WaitCondition::Changes).unwrap();
+        assert!(!check.observe(Some(&json!(100.5))));
+        assert!(!check.observe(Some(&json!(100.5))));
+        assert!(check.observe(Some(&json!(101.25))));
+
+        let mut check = WaitCheck::new(WaitCondition::Changes).unwrap();
+        assert!(!check.observe(None));
+        assert!(check.observe(Some(&json!("pending"))));
+    }
+
+    #[test]
+    fn wait_matches_regex() {
+        let mut check = WaitCheck::new(WaitCondition::Matches {
+            pattern: "^(filled|cancelled)$".into(),
+        })
+        .unwrap();
+        assert!(!check.observe(Some(&json!("pending"))));
+        assert!(check.observe(Some(&json!("filled"))));
+    }
+
+    #[test]
+    fn wait_invalid_regex() {
+        match WaitCheck::new(WaitCondition::Matches { pattern: "(".into() }) {
+            Err(DaemonError::InvalidPattern { pattern, .. }) => assert_eq!(pattern, "("),
+            _ => panic!("Expected InvalidPattern"),
+        }
+    }
+
+    // -- Machine-readable output tests ----------------------------------------
+
+    #[test]
+    fn format_json_is_pretty_and_keeps_markers() {
+        let value = node(json!({"home": {"#": {"href": "/", "method": "GET"}}}));
+        let output = format_hypermap_json(&value);
+        assert!(output.ends_with("}\n"));
+        let parsed: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed, json!({"home": {"#": {"href": "/", "method": "GET"}}}));
+        let yaml: Value = serde_yaml::from_str(&format_hypermap_yaml(&value)).unwrap();
+        assert_eq!(yaml, parsed);
+    }
+
+    #[test]
+    fn format_view_only_marks_controls() {
+        let value = node(json!({"#": {"scripts": ["/a.js"]}, "home": {"#": {"href": "/"}}}));
+        let parsed: Value = serde_json::from_str(&format_hypermap_view(&value)).unwrap();
+        assert_eq!(parsed, json!({"home": {"#": {"type": "control"}}}));
+    }
+
+    #[test]
+    fn formats_keep_the_server_key_order() {
+        let wire = r##"{"ticker": "ACRN", "name": "Acorn", "#": {}, "price": 201.5,
+            "buy": {"#": {"href": "/"}, "quantity": 0}}"##;
+        let value = node(serde_json::from_str(wire).unwrap());
+
+        let tree = format_hypermap_styled(&value, 0, false);
+        assert_eq!(tree, "ticker: ACRN\nname: Acorn\nprice: 201.5\nbuy@/\n  quantity: 0\n");
+        let json = format_hypermap_json(&value);
+        let keys: Vec<_> = ["ticker", "name", "price", "buy"]
+            .iter()
+            .map(|k| json.find(&format!("\"{}\"", k)).unwrap())
+            .collect();
+        assert!(keys.is_sorted());
+        assert!(format_hypermap_yaml(&value).starts_with("ticker: ACRN\nname: Acorn\n"));
+    }
+
+    #[test]
+    fn format_inspect_resolves_targets_and_lists_fields() {
+        let value = node(json!({
+            "#": {"scripts": ["/stocks.js"]},
+            "acrn": {
+                "price": 201.5,
+                "buy": {
+                    "#": {"href": "acrn/orders/", "method": "POST"},
+                    "quantity": 0,
+                    "limit": {"price": null}
+                },
+                "chart": {"#": {"href": "https://charts.example.com/acrn"}}
+            }
+        }));
+        let base = Url::parse("https://example.com/stocks/").unwrap();
+        assert_eq!(
+            format_inspect(&value, "", &base),
+            "(root)\n  scripts:\n    https://example.com/stocks.js\n\n\
+             acrn/buy@\n  method: POST\n  target: https://example.com/stocks/acrn/orders/\n\
+             \x20 fields:\n    quantity: 0\n    limit/\n      price: null\n\n\
+             acrn/chart@\n  method: GET\n  target: https://charts.example.com/acrn\n"
+        );
+
+        let buy = value.at_path("acrn/buy").unwrap();
+        assert!(format_inspect(buy, "acrn/buy", &base).starts_with("acrn/buy@\n"));
+        let price = value.at_path("acrn/price").unwrap();
+        assert_eq!(
+            format_inspect(price, "acrn/price", &base),
+            "No controls or scripts at acrn/price\n"
+        );
+    }
+
+    #[test]
+    fn format_yaml_nested() {
+        let value = node(json!({"market": {"acrn": {"price": 201.5, "ticker": "ACRN"}}}));
+        let output = format_hypermap_yaml(&value);
+        assert_eq!(output, "market:\n  acrn:\n    price: 201.5\n    ticker: ACRN\n");
+    }
+
+    #[test]
+    fn format_flat_leaves() {
+        let value = node(json!({"count": 3, "nav": {"home": "/"}, "status": null}));
+        let output = format_hypermap_flat(&value, "");
+        assert_eq!(output, "count\t3\nnav/home\t\"/\"\nstatus\tnull\n");
+    }
+
+    #[test]
+    fn format_flat_escapes_newlines() {
+        let value = node(json!({"note": "line one\nline two"}));
+        let output = format_hypermap_flat(&value, "");
+        assert_eq!(output, "note\t\"line one\\nline two\"\n");
+        assert_eq!(output.lines().count(), 1);
+    }
+
+    #[test]
+    fn number_tags_print_as_numbers() {
+        let value = node(json!({
+            "price": {"$number": "NaN"},
+            "low": {"$number": "-Infinity"}
+        }));
+        assert_eq!(format_hypermap_styled(&value, 0, false), "price: NaN\nlow: -Infinity\n");
+        assert_eq!(format_hypermap_flat(&value, ""), "price\tNaN\nlow\t-Infinity\n");
+    }
+
+    #[test]
+    fn format_flat_annotates_controls() {
+        let value = node(json!({"buy": {"#": {"href": "/"}, "quantity": 0}, "home": {"#": {"href": "/"}}}));
+        let output = format_hypermap_flat(&value, "");
+        assert_eq!(output, "buy\t@\nbuy/quantity\t0\nhome\t@\n");
+    }
+
+    #[test]
+    fn format_flat_arrays_and_empty_containers() {
+        let value = node(json!({"items": ["a", {}], "tags": []}));
+        let output = format_hypermap_flat(&value, "");
+        assert_eq!(output, "items/0\t\"a\"\nitems/1\t{}\ntags\t[]\n");
+    }
+
+    #[test]
+    fn format_flat_uses_prefix() {
+        let value = node(json!({"price": "201.32"}));
+        assert_eq!(
+            format_hypermap_flat(&value, "market/acrn"),
+            "market/acrn/price\t\"201.32\"\n"
+        );
+        assert_eq!(
+            format_hypermap_flat(&node(json!(42)), "market/acrn/price"),
+            "market/acrn/price\t42\n"
+        );
+    }
+
+    #[test]
+    fn format_flat_escapes_keys() {
+        let value = node(json!({"links": {"https://example.com/": 1, "": 2}}));
+        assert_eq!(
+            format_hypermap_flat(&value, ""),
+            "links/https:~1~1example.com~1\t1\n/links/\t2\n"
+        );
+    }
+
+    #[test]
+    fn format_matches_prints_each_under_its_path() {
+        let matches = vec![
+            ("market/acrn/price".to_string(), node(json!(12.5))),
+            ("market/ibm".to_string(), node(json!({"price": "201.32"}))),
+        ];
+        assert_eq!(
+            format_matches_styled(&matches, false),
+            "market/acrn/price: 12.5\nmarket/ibm/\n  price: 201.32\n"
+        );
+        assert_eq!(
+            format_matches_flat(&matches),
+            "market/acrn/price\t12.5\nmarket/ibm/price\t\"201.32\"\n"
+        );
+        let json: Value = serde_json::from_str(&format_matches_json(&matches)).unwrap();
+        assert_eq!(json, json!({"market/acrn/price": 12.5, "market/ibm": {"price": "201.32"}}));
+        let controls = vec![("buy".to_string(), node(json!({"#": {"href": "/buy"}})))];
+        let json: Value = serde_json::from_str(&format_matches_json(&controls)).unwrap();
+        assert_eq!(json, json!({"buy": {"#": {"href": "/buy"}}}));
+        let view: Value = serde_json::from_str(&format_matches_view(&controls)).unwrap();
+        assert_eq!(view, json!({"buy": {"#": {"type": "control"}}}));
+        assert_eq!(format_matches_styled(&[], false), "No matches\n");
+        assert_eq!(format_matches_json(&[]), "{}\n");
+    }
+
+    // -- find tests -----------------------------------------------------------
+
+    fn find(pattern: &str, regex: bool, ignore_case: bool) -> String {
+        let value = node(json!({
+            "market": {
+                "acrn": {
+                    "ticker": "ACRN",
+                    "price": 12.5,
+                    "buy": {"#": {"href": "/acrn/buy", "method": "POST"}, "quantity": 0}
+                }
+            },
+            "news": ["ACRN up", "IBM flat"]
+        }));
+        let finder = Finder::new(pattern, regex, ignore_case).unwrap();
+        format_found(&finder.find(&value, ""), false)
+    }
+
+    #[test]
+    fn find_searches_keys_and_values() {
+        assert_eq!(find("acrn", false, false), "market/acrn/\n");
+        assert_eq!(
+            find("acrn", false, true),
+            "market/acrn/\nmarket/acrn/ticker: ACRN\nnews/0: ACRN up\n"
+        );
+        assert_eq!(find("buy", false, false), "market/acrn/buy@/\n");
+        assert_eq!(find("12.5", false, false), "market/acrn/price: 12.5\n");
+        assert_eq!(find("POST", false, false), "No matches\n");
+    }
+
+    #[test]
+    fn find_takes_regular_expressions() {
+        assert_eq!(
+            find("^(price|quantity)$", true, false),
+            "market/acrn/price: 12.5\nmarket/acrn/buy/quantity: 0\n"
+        );
+        assert_eq!(find("a.r", false, true), "No matches\n");
+        assert!(matches!(
+            Finder::new("(", true, false),
+            Err(DaemonError::InvalidPattern { .. })
+        ));
+    }
+
+    #[test]
+    fn find_prints_paths_below_a_prefix() {
+        let value = node(json!({"price": 12.5, "a/b": "x"}));
+        let finder = Finder::new("", false, false).unwrap();
+        assert_eq!(
+            format_found(&finder.find(&value, "market/acrn"), false),
+            "market/acrn/price: 12.5\nmarket/acrn/a~1b: x\n"
+        );
+    }
+
+    // -- controls tests -------------------------------------------------------
+
+    #[test]
+    fn list_controls_resolves_targets_and_filters_by_method() {
+        let value = node(json!({
+            "#": {"href": "/stocks/"},
+            "acrn": {
+                "buy": {"#": {"href": "acrn/orders/", "method": "post"}, "quantity": 0},
+                "chart": {"#": {"href": "https://charts.example.com/acrn"}}
+            }
+        }));
+        let base = Url::parse("https://example.com/stocks/").unwrap();
+        let controls = ControlInfo::list(&value, &base, None);
+        assert_eq!(
+            controls,
+            [
+                ControlInfo {
+                    path: String::new(),
+                    method: "GET".to_string(),
+                    target: "https://example.com/stocks/".to_string(),
+                },
+                ControlInfo {
+                    path: "acrn/buy".to_string(),
+                    method: "POST".to_string(),
+                    target: "https://example.com/stocks/acrn/orders/".to_string(),
+                },
+                ControlInfo {
+                    path: "acrn/chart".to_string(),
+                    method: "GET".to_string(),
+                    target: "https://charts.example.com/acrn".to_string(),
+                },
+            ]
+        );
+
+        let posts = ControlInfo::list(&value, &base, Some("Post"));
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].path, "acrn/buy");
+        assert!(ControlInfo::list(&value, &base, Some("DELETE")).is_empty());
+    }
+
+    #[test]
+    fn format_controls_aligns_columns() {
+        let control = |path: &str, method: &str, target: &str| ControlInfo {
+            path: path.to_string(),
+            method: method.to_string(),
+            target: target.to_string(),
+        };
+        let controls = [
+            control("", "GET", "https://example.com/"),
+            control("acrn/buy", "POST", "https://example.com/acrn/orders/"),
+        ];
+        assert_eq!(
+            format_controls(&controls),
+            "GET   (root)    https://example.com/\n\
+             POST  acrn/buy  https://example.com/acrn/orders/\n"
+        );
+        assert_eq!(format_controls(&[]), "No controls\n");
+    }
+
+    #[test]
+    fn format_request_prints_the_body_after_the_url() {
+        let get = RequestInfo {
+            method: "GET".to_string(),
+            url: "https://example.com/search?q=x+y".to_string(),
+            body: None,
+        };
+        assert_eq!(format_request(&get), "GET https://example.com/search?q=x+y\n");
+
+        let post = RequestInfo {
+            method: "POST".to_string(),
+            url: "https://example.com/orders/".to_string(),
+            body: Some(json!({"quantity": "5"})),
+        };
+        assert_eq!(
+            format_request(&post),
+            "POST https://example.com/orders/\n{\n  \"quantity\": \"5\"\n}\n"
+        );
+    }
+
+    // -- format_tabs tests ----------------------------------------------------
+
+    #[test]
+    fn format_tabs_empty() {
+        assert_eq!(format_tabs(&[]), "No open tabs\n");
+    }
+
+    #[test]
+    fn format_tabs_lists_names_loading_state_and_native_tabs() {
+        let tabs = vec![
+            TabInfo {
+                id: 1,
+                name: Some("stocks".into()),
+                url: "https://example.com/stocks/".into(),
+                state: LoadState::Complete,
+                backend: Backend::Servo,
+            },
+            TabInfo {
+                id: 2,
+                name: None,
+                url: "https://example.com/".into(),
+                state: LoadState::Loading,
+                backend: Backend::Servo,
+            },
+            TabInfo {
+                id: 4,
+                name: None,
+                url: "https://example.com/api/".into(),
+                state: LoadState::Loading,
+                backend: Backend::Native,
+            },
+        ];
+        assert_eq!(
+            format_tabs(&tabs),
+            "1 (stocks)  https://example.com/stocks/\n\
+             2  https://example.com/  [loading]\n\
+             4  https://example.com/api/  [native] [loading]\n"
+        );
+    }
+
+    // -- format_history tests -------------------------------------------------
+
+    #[test]
+    fn format_history_marks_current() {
+        let history = HistoryInfo {
+            entries: vec![
+                "https://example.com/".into(),
+                "https://example.com/stocks".into(),
+                "https://example.com/orders/7".into(),
+            ],
+            current: 1,
+        };
+        assert_eq!(
+            format_history(&history),
+            "  1  https://example.com/\n* 2  https://example.com/stocks\n  3  https://example.com/orders/7\n"
+        );
     }
 
     // -- format_hypermap tests ------------------------------------------------
 
     #[test]
     fn format_hypermap_simple_value() {
-        let value = json!({"price": 42});
+        let value = node(json!({"price": 42}));
         let output = format_hypermap(&value, 0);
         assert_eq!(output, "price: 42\n");
     }
 
     #[test]
     fn format_hypermap_string_value() {
-        let value = json!({"name": "test"});
+        let value = node(json!({"name": "test"}));
         let output = format_hypermap(&value, 0);
         assert_eq!(output, "name: test\n");
     }
 
     #[test]
     fn format_hypermap_null_value() {
-        let value = json!({"status": null});
+        let value = node(json!({"status": null}));
         let output = format_hypermap(&value, 0);
         assert_eq!(output, "status: null\n");
     }
 
     #[test]
     fn format_hypermap_bool_value() {
-        let value = json!({"active": true});
+        let value = node(json!({"active": true}));
         let output = format_hypermap(&value, 0);
         assert_eq!(output, "active: true\n");
     }
 
     #[test]
     fn format_hypermap_nested_object() {
-        let value = json!({"nav": {"home": "/"} });
+        let value = node(json!({"nav": {"home": "/"} }));
         let output = format_hypermap(&value, 0);
         // nav has children, so it gets /
         assert_eq!(output, "nav/\n  home: /\n");
@@ -606,7 +2031,7 @@ mod tests {
 
     #[test]
     fn format_hypermap_array() {
-        let value = json!({"items": [1, 2, 3]});
+        let value = node(json!({"items": [1, 2, 3]}));
         let output = format_hypermap(&value, 0);
         // items has children (array), so it gets /
         assert_eq!(output, "items/\n  [0]: 1\n  [1]: 2\n  [2]: 3\n");
@@ -614,8 +2039,8 @@ mod tests {
 
     #[test]
     fn format_hypermap_control_no_children() {
-        // Control is an object with only "#" key containing type: "control"
-        let value = json!({"home": {"#": {"type": "control"}}});
+        // Control is an object with only "#" key containing an href
+        let value = node(json!({"home": {"#": {"href": "/"}}}));
         let output = format_hypermap(&value, 0);
         // Control with no children gets @ suffix (no bold without color flag)
         assert_eq!(output, "home@\n");
@@ -624,7 +2049,7 @@ mod tests {
     #[test]
     fn format_hypermap_control_with_color() {
         // Control with color enabled
-        let value = json!({"home": {"#": {"type": "control"}}});
+        let value = node(json!({"home": {"#": {"href": "/"}}}));
         let output = format_hypermap_styled(&value, 0, true);
         // Control with color gets bold
         assert_eq!(output, "\x1b[1mhome@\x1b[0m\n");
@@ -633,7 +2058,7 @@ mod tests {
     #[test]
     fn format_hypermap_control_with_children() {
         // Control with additional children beyond "#"
-        let value = json!({"nav": {"#": {"type": "control"}, "label": "Home"}});
+        let value = node(json!({"nav": {"#": {"href": "/"}, "label": "Home"}}));
         let output = format_hypermap(&value, 0);
         // Control with children gets @/ suffix
         assert_eq!(output, "nav@/\n  label: Home\n");
@@ -642,15 +2067,22 @@ mod tests {
     #[test]
     fn format_hypermap_control_with_children_color() {
         // Control with additional children beyond "#", with color
-        let value = json!({"nav": {"#": {"type": "control"}, "label": "Home"}});
+        let value = node(json!({"nav": {"#": {"href": "/"}, "label": "Home"}}));
         let output = format_hypermap_styled(&value, 0, true);
         // Control with children gets @/ suffix and bold
         assert_eq!(output, "\x1b[1mnav@/\x1b[0m\n  label: Home\n");
     }
 
+    #[test]
+    fn format_hypermap_marks_controls_in_lists() {
+        let value = node(json!({"todos": [{"#": {"href": "/todos/1"}, "title": "Ship"}]}));
+        let output = format_hypermap(&value, 0);
+        assert_eq!(output, "todos/\n  [0]@/\n    title: Ship\n");
+    }
+
     #[test]
     fn format_hypermap_skips_hash_key() {
-        let value = json!({"#": {"type": "control"}, "name": "test"});
+        let value = node(json!({"#": {"href": "/"}, "name": "test"}));
         let output = format_hypermap(&value, 0);
         // "#" key should not appear in output
         assert!(!output.contains("#"));
@@ -659,7 +2091,7 @@ mod tests {
 
     #[test]
     fn format_hypermap_with_indent() {
-        let value = json!({"a": 1});
+        let value = node(json!({"a": 1}));
         let output = format_hypermap(&value, 2);
         assert_eq!(output, "    a: 1\n");
     }
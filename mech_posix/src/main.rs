@@ -3,16 +3,25 @@
 // This is the lightweight client binary. It communicates with the mechd daemon
 // over a Unix socket using the varlink protocol (JSON + null-byte framing).
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::Value;
 use std::collections::HashMap;
-use std::io::{IsTerminal, Read};
+use std::io::{IsTerminal, Read, Write};
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::process::Command;
+use std::time::Duration;
+use url::Url;
 
-use mech_cli::{cleanup, socket_path, write_message, DaemonCommand, DaemonReply};
-
-#[cfg(test)]
-use mech_cli::format_hypermap_styled;
+use mech_cli::fetch::{fetch, Request};
+use mech_cli::{
+    cleanup, format_controls, format_found, format_history, format_hypermap_flat,
+    format_hypermap_json, format_hypermap_styled, format_hypermap_view, format_hypermap_yaml,
+    format_inspect, format_matches_flat, format_matches_json, format_matches_styled,
+    format_matches_view, format_matches_yaml, format_request, format_tabs, is_selector,
+    socket_path, validate, Backend, DaemonCommand, DaemonError, Finder, MechClient, Node,
+    WaitCondition, HYPERMAP_MEDIA_TYPE,
+};
 
 #[derive(Parser)]
 #[command(name = "mech", about = "CLI for interacting with HyperMap resources")]
@@ -28,6 +37,9 @@ enum Commands {
         /// Run in foreground (don't daemonize)
         #[arg(short, long)]
         foreground: bool,
+        /// Backend for tabs opened without --backend
+        #[arg(short, long, value_enum)]
+        backend: Option<Backend>,
     },
     /// Stop the daemon
     Stop,
@@ -38,12 +50,51 @@ enum Commands {
         /// Optional name for the tab
         #[arg(short, long)]
         name: Option<String>,
+        /// What to load the tab with (default: the daemon's)
+        #[arg(short, long, value_enum)]
+        backend: Option<Backend>,
+        /// Seconds to wait for the page to load (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
     },
     /// Show tab contents, optionally at a specific path
     Show {
         /// Tab reference with optional path (e.g., "1", "stocks", "1:nav/home")
         #[arg(value_name = "TAB[:PATH]")]
         target: String,
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Tree)]
+        output: OutputFormat,
+    },
+    /// Show where controls go and what they send, at or below a path
+    Inspect {
+        /// Tab reference with optional path (e.g., "1", "stocks:market/acrn/buy")
+        #[arg(value_name = "TAB[:PATH]")]
+        target: String,
+    },
+    /// List every control in a tab with its method and target
+    Controls {
+        /// Tab reference (ID or name)
+        #[arg(value_name = "TAB")]
+        tab: String,
+        /// Only list controls activated with this method (e.g., GET)
+        #[arg(short, long)]
+        method: Option<String>,
+    },
+    /// Search a tab's keys and values, printing the paths that match
+    Find {
+        /// Tab reference with optional path to search below (e.g., "1", "stocks:market")
+        #[arg(value_name = "TAB[:PATH]")]
+        target: String,
+        /// Text to look for
+        #[arg(value_name = "PATTERN")]
+        pattern: String,
+        /// Treat PATTERN as a regular expression
+        #[arg(short, long)]
+        regex: bool,
+        /// Ignore case when matching
+        #[arg(short, long)]
+        ignore_case: bool,
     },
     /// Set a value at a path (input without triggering control)
     Set {
@@ -53,15 +104,57 @@ enum Commands {
         /// Value to set
         #[arg(value_name = "VALUE")]
         value: String,
+        /// Parse VALUE as JSON, to set a number, boolean or null
+        #[arg(long)]
+        json: bool,
     },
     /// Use a control at a path
     Use {
         /// Tab and path (e.g., "1:nav/home", "stocks:submit")
         #[arg(value_name = "TAB:PATH")]
         target: String,
-        /// Form data as key=value pairs
-        #[arg(value_name = "KEY=VALUE")]
-        data: Vec<String>,
+        /// Form data as key=value pairs, or key:=json for numbers, booleans
+        /// and null
+        #[arg(value_name = "KEY=VALUE", value_parser = parse_field)]
+        data: Vec<(String, Value)>,
+        /// Seconds to wait for a resulting page load (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+        /// Print the request the control would make, without making it
+        #[arg(long, conflicts_with = "timeout")]
+        dry_run: bool,
+    },
+    /// Wait until the value at a path satisfies a condition
+    ///
+    /// Without a condition, waits for the path to exist. Exits with status 2
+    /// if the timeout expires first.
+    Wait {
+        /// Tab and path (e.g., "orders:order/status")
+        #[arg(value_name = "TAB:PATH")]
+        target: String,
+        /// Wait until the value equals VALUE
+        #[arg(long, value_name = "VALUE", group = "condition")]
+        equals: Option<String>,
+        /// Wait until the value matches a regular expression
+        #[arg(long, value_name = "REGEX", group = "condition")]
+        matches: Option<String>,
+        /// Wait until the value differs from its current one
+        #[arg(long, group = "condition")]
+        changes: bool,
+        /// Seconds to wait before giving up
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+    },
+    /// Print the contents of a tab each time they change
+    ///
+    /// Runs until interrupted or the tab is closed.
+    Watch {
+        /// Tab reference with optional path (e.g., "1", "stocks:market/acrn")
+        #[arg(value_name = "TAB[:PATH]")]
+        target: String,
+        /// Output format; json prints one compact line per update
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Tree)]
+        output: OutputFormat,
     },
     /// Fork a tab (create a copy)
     Fork {
@@ -71,65 +164,262 @@ enum Commands {
         /// Optional name for the new tab
         #[arg(short, long)]
         name: Option<String>,
+        /// Seconds to wait for the copy to be restored (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
     },
     /// Close a tab
     Close {
-        /// Tab reference (index or name)
+        /// Tab reference (ID or name)
         #[arg(value_name = "TAB")]
         tab: String,
     },
     /// Name or rename a tab
     Name {
-        /// Tab reference (index or current name)
+        /// Tab reference (ID or current name)
         #[arg(value_name = "TAB")]
         tab: String,
         /// New name for the tab
         #[arg(value_name = "NAME")]
         name: String,
     },
+    /// Load a URL into an existing tab
+    Goto {
+        /// Tab reference (ID or name)
+        #[arg(value_name = "TAB")]
+        tab: String,
+        /// URL to load, absolute or relative to the tab's current URL
+        url: String,
+        /// Seconds to wait for the page to load (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+    },
+    /// Go back one page in a tab's history
+    Back {
+        /// Tab reference (ID or name)
+        #[arg(value_name = "TAB")]
+        tab: String,
+        /// Seconds to wait for the page to load (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+    },
+    /// Go forward one page in a tab's history
+    Forward {
+        /// Tab reference (ID or name)
+        #[arg(value_name = "TAB")]
+        tab: String,
+        /// Seconds to wait for the page to load (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+    },
+    /// Load a tab's current page again
+    Reload {
+        /// Tab reference (ID or name)
+        #[arg(value_name = "TAB")]
+        tab: String,
+        /// Seconds to wait for the page to load (0 = don't wait)
+        #[arg(short, long, value_name = "SECONDS", value_parser = parse_timeout)]
+        timeout: Option<Duration>,
+    },
+    /// List the pages in a tab's history
+    History {
+        /// Tab reference (ID or name)
+        #[arg(value_name = "TAB")]
+        tab: String,
+    },
     /// List all open tabs
     Tabs,
+    /// Check a HyperMap resource against the spec
+    Validate {
+        /// A file ("-" for stdin), an http(s) URL, or a tab reference
+        #[arg(value_name = "FILE|URL|TAB")]
+        source: String,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Indented tree with @ and / markers
+    Tree,
+    /// Pretty-printed JSON, with every # key
+    Json,
+    /// YAML document, with every # key
+    Yaml,
+    /// One path<TAB>value line per leaf, with controls marked @
+    Flat,
+    /// Pretty-printed JSON in the spec's toView() form
+    View,
 }
 
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Start { foreground } => start_daemon(foreground),
+        Commands::Start {
+            foreground,
+            backend,
+        } => start_daemon(foreground, backend),
         Commands::Stop => stop_daemon(),
-        Commands::Open { url, name } => {
-            send_command(&DaemonCommand::Open { url, name });
+        Commands::Open {
+            url,
+            name,
+            backend,
+            timeout,
+        } => {
+            let timeout_ms = timeout.map(|t| t.as_millis() as u64);
+            send_command(&DaemonCommand::Open {
+                url,
+                name,
+                backend,
+                timeout_ms,
+            });
+        }
+        Commands::Show { target, output } => {
+            let (tab, path) = parse_target(&target);
+            if let Some(selector) = path.as_deref().filter(|p| is_selector(p)) {
+                let matches = exit_on_error(MechClient::new().select(&tab, selector));
+                let rendered = match output {
+                    OutputFormat::Tree => {
+                        format_matches_styled(&matches, std::io::stdout().is_terminal())
+                    }
+                    OutputFormat::Json => format_matches_json(&matches),
+                    OutputFormat::Yaml => format_matches_yaml(&matches),
+                    OutputFormat::Flat => format_matches_flat(&matches),
+                    OutputFormat::View => format_matches_view(&matches),
+                };
+                print!("{}", rendered);
+                return;
+            }
+            let node = exit_on_error(MechClient::new().show(&tab, path.as_deref()));
+            let rendered = match output {
+                OutputFormat::Tree => {
+                    let color = std::io::stdout().is_terminal();
+                    format_hypermap_styled(&node, 0, color)
+                }
+                OutputFormat::Json => format_hypermap_json(&node),
+                OutputFormat::Yaml => format_hypermap_yaml(&node),
+                OutputFormat::Flat => format_hypermap_flat(&node, path.as_deref().unwrap_or("")),
+                OutputFormat::View => format_hypermap_view(&node),
+            };
+            print!("{}", rendered);
         }
-        Commands::Show { target } => {
+        Commands::Inspect { target } => {
             let (tab, path) = parse_target(&target);
-            let color = std::io::stdout().is_terminal();
-            send_command(&DaemonCommand::Show { tab, path, color });
+            let (node, url) = exit_on_error(MechClient::new().inspect(&tab, path.as_deref()));
+            print!("{}", format_inspect(&node, path.as_deref().unwrap_or(""), &url));
+        }
+        Commands::Controls { tab, method } => {
+            let controls = exit_on_error(MechClient::new().controls(&tab, method.as_deref()));
+            print!("{}", format_controls(&controls));
         }
-        Commands::Set { target, value } => {
+        Commands::Find {
+            target,
+            pattern,
+            regex,
+            ignore_case,
+        } => {
+            let finder = exit_on_error(Finder::new(&pattern, regex, ignore_case));
+            let (tab, path) = parse_target(&target);
+            let node = exit_on_error(MechClient::new().show(&tab, path.as_deref()));
+            let found = finder.find(&node, path.as_deref().unwrap_or(""));
+            print!("{}", format_found(&found, std::io::stdout().is_terminal()));
+        }
+        Commands::Set {
+            target,
+            value,
+            json,
+        } => {
             let (tab, path) = parse_target(&target);
             let Some(path) = path else {
                 eprintln!("error: set requires a path (e.g., \"{}:path/to/field\")", tab);
                 std::process::exit(1);
             };
+            let value = if json {
+                parse_json_value(&value).unwrap_or_else(|e| {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                })
+            } else {
+                Value::String(value)
+            };
             send_command(&DaemonCommand::Set { tab, path, value });
         }
-        Commands::Use { target, data } => {
+        Commands::Use {
+            target,
+            data,
+            timeout,
+            dry_run,
+        } => {
             let (tab, path) = parse_target(&target);
             let Some(path) = path else {
                 eprintln!("error: use requires a path (e.g., \"{}:path/to/control\")", tab);
                 std::process::exit(1);
             };
-            let data: HashMap<String, String> = data
-                .iter()
-                .filter_map(|s| {
-                    let mut split = s.splitn(2, '=');
-                    Some((split.next()?.to_string(), split.next()?.to_string()))
-                })
-                .collect();
-            send_command(&DaemonCommand::Use { tab, path, data });
+            let data: HashMap<String, Value> = data.into_iter().collect();
+            if dry_run {
+                let request = exit_on_error(MechClient::new().preview(&tab, &path, &data));
+                print!("{}", format_request(&request));
+            } else {
+                let client = client_with_timeout(timeout);
+                println!("{}", exit_on_error(client.use_control(&tab, &path, &data)));
+            }
         }
-        Commands::Fork { tab, name } => {
-            send_command(&DaemonCommand::Fork { tab, name });
+        Commands::Wait {
+            target,
+            equals,
+            matches,
+            changes,
+            timeout,
+        } => {
+            let (tab, path) = parse_target(&target);
+            let Some(path) = path else {
+                eprintln!("error: wait requires a path (e.g., \"{}:path/to/value\")", tab);
+                std::process::exit(1);
+            };
+            let condition = if let Some(value) = equals {
+                WaitCondition::Equals { value }
+            } else if let Some(pattern) = matches {
+                WaitCondition::Matches { pattern }
+            } else if changes {
+                WaitCondition::Changes
+            } else {
+                WaitCondition::Exists
+            };
+            match client_with_timeout(timeout).wait(&tab, &path, condition) {
+                Ok(node) => print!("{}", format_hypermap_json(&node)),
+                Err(err) => {
+                    eprintln!("{}", err.user_message());
+                    // Let scripts tell "not yet" apart from real failures
+                    let code = if matches!(err, DaemonError::Timeout { .. }) { 2 } else { 1 };
+                    std::process::exit(code);
+                }
+            }
+        }
+        Commands::Watch { target, output } => {
+            let (tab, path) = parse_target(&target);
+            let updates = exit_on_error(MechClient::new().watch(&tab, path.as_deref()));
+            let mut stdout = std::io::stdout();
+            let color = stdout.is_terminal();
+            for update in updates {
+                let node = exit_on_error(update);
+                let rendered = format_watch_update(&node, output, path.as_deref(), color);
+                // Stop quietly once the reader goes away (e.g. `| head`)
+                if stdout
+                    .write_all(rendered.as_bytes())
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+        Commands::Fork { tab, name, timeout } => {
+            let timeout_ms = timeout.map(|t| t.as_millis() as u64);
+            send_command(&DaemonCommand::Fork {
+                tab,
+                name,
+                timeout_ms,
+            });
         }
         Commands::Close { tab } => {
             send_command(&DaemonCommand::Close { tab });
@@ -137,15 +427,51 @@ fn main() {
         Commands::Name { tab, name } => {
             send_command(&DaemonCommand::Name { tab, name });
         }
+        Commands::Goto { tab, url, timeout } => {
+            let url = exit_on_error(client_with_timeout(timeout).goto(&tab, &url));
+            println!("{}", url);
+        }
+        Commands::Back { tab, timeout } => {
+            let url = exit_on_error(client_with_timeout(timeout).back(&tab));
+            println!("{}", url);
+        }
+        Commands::Forward { tab, timeout } => {
+            let url = exit_on_error(client_with_timeout(timeout).forward(&tab));
+            println!("{}", url);
+        }
+        Commands::Reload { tab, timeout } => {
+            let url = exit_on_error(client_with_timeout(timeout).reload(&tab));
+            println!("{}", url);
+        }
+        Commands::History { tab } => {
+            let history = exit_on_error(MechClient::new().history(&tab));
+            print!("{}", format_history(&history));
+        }
         Commands::Tabs => {
-            send_command(&DaemonCommand::Tabs);
+            let tabs = exit_on_error(MechClient::new().tabs());
+            print!("{}", format_tabs(&tabs));
         }
+        Commands::Validate { source } => validate_resource(&source),
     }
 }
 
 /// Parse a target string like "1", "stocks", "1:nav/home", or "stocks:nav/home"
 /// Returns (tab_ref, optional_path)
+///
+/// The path starts after the first colon, so it may contain colons itself. A
+/// tab name that contains one is written as a JSON string, as in
+/// `"a:b":nav/home`.
 fn parse_target(target: &str) -> (String, Option<String>) {
+    if target.starts_with('"') {
+        let mut names = serde_json::Deserializer::from_str(target).into_iter::<String>();
+        if let Some(Ok(tab)) = names.next() {
+            match &target[names.byte_offset()..] {
+                "" => return (tab, None),
+                rest if rest.starts_with(':') => return (tab, Some(rest[1..].to_string())),
+                _ => {}
+            }
+        }
+    }
     if let Some(colon_pos) = target.find(':') {
         let tab = target[..colon_pos].to_string();
         let path = target[colon_pos + 1..].to_string();
@@ -155,44 +481,134 @@ fn parse_target(target: &str) -> (String, Option<String>) {
     }
 }
 
-fn send_command(cmd: &DaemonCommand) {
-    match UnixStream::connect(socket_path()) {
-        Ok(mut stream) => {
-            let json = serde_json::to_vec(cmd).expect("Failed to serialize command");
-            if let Err(e) = write_message(&mut stream, &json) {
-                eprintln!("Failed to send command: {}", e);
-                return;
-            }
-            let _ = stream.shutdown(std::net::Shutdown::Write);
-            let mut buf = Vec::new();
-            if stream.read_to_end(&mut buf).is_ok() && !buf.is_empty() {
-                if buf.last() == Some(&0) {
-                    buf.pop();
-                }
-                match serde_json::from_slice::<DaemonReply>(&buf) {
-                    Ok(DaemonReply::Ok { parameters }) => {
-                        if !parameters.message.is_empty() {
-                            print!("{}", parameters.message);
-                        }
-                    }
-                    Ok(DaemonReply::Err(err)) => {
-                        eprintln!("{}", err.user_message());
-                        std::process::exit(1);
-                    }
-                    Err(_) => {
-                        eprintln!("Invalid response from daemon. Is mechd up to date?");
-                        std::process::exit(1);
-                    }
-                }
-            }
+/// Render one `mech watch` update so that successive updates stay apart:
+/// JSON as a single line, YAML as its own document, and the line-based
+/// formats followed by a blank line.
+fn format_watch_update(
+    node: &Node,
+    output: OutputFormat,
+    path: Option<&str>,
+    color: bool,
+) -> String {
+    match output {
+        OutputFormat::Tree => format!("{}\n", format_hypermap_styled(node, 0, color)),
+        OutputFormat::Json => format!("{}\n", node.to_json()),
+        OutputFormat::Yaml => format!("---\n{}", format_hypermap_yaml(node)),
+        OutputFormat::Flat => format!("{}\n", format_hypermap_flat(node, path.unwrap_or(""))),
+        OutputFormat::View => format!("{}\n", node.to_view()),
+    }
+}
+
+/// Load a resource from a file, URL or tab and print every way in which it
+/// breaks the spec, exiting with status 1 if it does.
+fn validate_resource(source: &str) {
+    let mut problems = Vec::new();
+    let text = if source.starts_with("http://") || source.starts_with("https://") {
+        let response = Url::parse(source)
+            .map_err(|e| e.to_string())
+            .and_then(|url| fetch(&Request::get(url)).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to fetch {}: {}", source, e);
+                std::process::exit(1);
+            });
+        if !response.is_hypermap() {
+            problems.push(format!(
+                "Content-Type: served as '{}', not {}",
+                response.content_type, HYPERMAP_MEDIA_TYPE
+            ));
         }
-        Err(_) => {
-            eprintln!("Failed to connect to daemon. Is it running? Try: mech start");
+        Some(response.body)
+    } else if source == "-" {
+        let mut body = String::new();
+        std::io::stdin().read_to_string(&mut body).unwrap_or_else(|e| {
+            eprintln!("Failed to read stdin: {}", e);
+            std::process::exit(1);
+        });
+        Some(body)
+    } else if Path::new(source).exists() {
+        Some(std::fs::read_to_string(source).unwrap_or_else(|e| {
+            eprintln!("Failed to read {}: {}", source, e);
+            std::process::exit(1);
+        }))
+    } else {
+        None
+    };
+
+    let resource = match text {
+        Some(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            eprintln!("{} is not JSON: {}", source, e);
+            std::process::exit(1);
+        }),
+        // Anything else names a tab
+        None => exit_on_error(MechClient::new().show(source, None)).to_json(),
+    };
+    problems.extend(validate(&resource).iter().map(ToString::to_string));
+
+    if problems.is_empty() {
+        println!("Valid HyperMap resource");
+    } else {
+        for problem in &problems {
+            println!("{}", problem);
         }
+        std::process::exit(1);
     }
 }
 
-fn start_daemon(foreground: bool) {
+/// Parse a value given as JSON, which must be a string, number, boolean or
+/// null as those are all an input can hold.
+fn parse_json_value(s: &str) -> Result<Value, String> {
+    match serde_json::from_str(s) {
+        Ok(Value::Array(_) | Value::Object(_)) => Err(format!(
+            "'{}' is not a string, number, boolean or null",
+            s
+        )),
+        Ok(value) => Ok(value),
+        Err(e) => Err(format!("'{}' is not valid JSON: {}", s, e)),
+    }
+}
+
+/// Parse a `key=value` form field, whose value is a string, or a
+/// `key:=json` one whose value is JSON.
+fn parse_field(s: &str) -> Result<(String, Value), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!("'{}' is not key=value or key:=json", s));
+    };
+    match key.strip_suffix(':') {
+        Some(key) => Ok((key.to_string(), parse_json_value(value)?)),
+        None => Ok((key.to_string(), Value::String(value.to_string()))),
+    }
+}
+
+/// Parse a `--timeout` value given in (possibly fractional) seconds.
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("'{}' is not a number of seconds", s))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("'{}' is not a valid timeout", s))
+}
+
+/// A client that passes `--timeout` on to the daemon when it was given.
+fn client_with_timeout(timeout: Option<Duration>) -> MechClient {
+    match timeout {
+        Some(timeout) => MechClient::new().with_timeout(timeout),
+        None => MechClient::new(),
+    }
+}
+
+fn send_command(cmd: &DaemonCommand) {
+    let reply = exit_on_error(MechClient::new().call(cmd));
+    if !reply.message.is_empty() {
+        print!("{}", reply.message);
+    }
+}
+
+/// Unwrap a daemon result, printing the error and exiting on failure.
+fn exit_on_error<T>(result: Result<T, DaemonError>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err.user_message());
+        std::process::exit(1);
+    })
+}
+
+fn start_daemon(foreground: bool, backend: Option<Backend>) {
     // Check if already running
     if UnixStream::connect(socket_path()).is_ok() {
         eprintln!("Daemon already running");
@@ -206,6 +622,9 @@ fn start_daemon(foreground: bool) {
     if foreground {
         cmd.arg("--foreground");
     }
+    if let Some(backend) = backend {
+        cmd.args(["--backend", &backend.to_string()]);
+    }
 
     match cmd.spawn() {
         Ok(mut child) => {
@@ -283,9 +702,139 @@ mod tests {
         assert_eq!(path, Some("submit".to_string()));
     }
 
+    #[test]
+    fn parse_target_path_with_colons() {
+        let (tab, path) = parse_target("1:links/https:~1~1example.com");
+        assert_eq!(tab, "1");
+        assert_eq!(path, Some("links/https:~1~1example.com".to_string()));
+    }
+
+    #[test]
+    fn parse_target_quoted_name() {
+        assert_eq!(parse_target(r#""a:b""#), ("a:b".to_string(), None));
+        let (tab, path) = parse_target(r#""a:b":nav/home"#);
+        assert_eq!(tab, "a:b");
+        assert_eq!(path, Some("nav/home".to_string()));
+        let (tab, path) = parse_target(r#""a\"b":"#);
+        assert_eq!(tab, "a\"b");
+        assert_eq!(path, Some(String::new()));
+        // Not a whole JSON string before the colon: split as usual
+        let (tab, path) = parse_target(r#""a"b:c"#);
+        assert_eq!(tab, r#""a"b"#);
+        assert_eq!(path, Some("c".to_string()));
+    }
+
+    #[test]
+    fn show_output_defaults_to_tree() {
+        let cli = Cli::try_parse_from(["mech", "show", "1"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Show { output: OutputFormat::Tree, .. }
+        ));
+    }
+
+    #[test]
+    fn show_output_flag() {
+        let cli = Cli::try_parse_from(["mech", "show", "1:market", "--output", "flat"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Show { output: OutputFormat::Flat, .. }
+        ));
+        assert!(Cli::try_parse_from(["mech", "show", "1", "-o", "xml"]).is_err());
+    }
+
+    #[test]
+    fn timeout_flag_accepts_fractional_seconds() {
+        let cli = Cli::try_parse_from(["mech", "use", "1:next", "--timeout", "0.5"]).unwrap();
+        match cli.command {
+            Commands::Use { timeout, .. } => assert_eq!(timeout, Some(Duration::from_millis(500))),
+            _ => panic!("Expected Use"),
+        }
+        let cli = Cli::try_parse_from(["mech", "open", "example.com", "-t", "0"]).unwrap();
+        match cli.command {
+            Commands::Open { timeout, .. } => assert_eq!(timeout, Some(Duration::ZERO)),
+            _ => panic!("Expected Open"),
+        }
+        assert!(Cli::try_parse_from(["mech", "open", "example.com", "-t", "-1"]).is_err());
+        assert!(Cli::try_parse_from(["mech", "open", "example.com", "-t", "soon"]).is_err());
+    }
+
+    #[test]
+    fn fields_are_strings_unless_given_as_json() {
+        assert_eq!(parse_field("note=a=b"), Ok(("note".to_string(), json!("a=b"))));
+        assert_eq!(parse_field("quantity=100"), Ok(("quantity".to_string(), json!("100"))));
+        assert_eq!(parse_field("quantity:=100"), Ok(("quantity".to_string(), json!(100))));
+        assert_eq!(parse_field("limit/open:=true"), Ok(("limit/open".to_string(), json!(true))));
+        assert_eq!(parse_field("note:=null"), Ok(("note".to_string(), Value::Null)));
+        assert_eq!(parse_field("note:=\"100\""), Ok(("note".to_string(), json!("100"))));
+        assert!(parse_field("quantity").is_err());
+        assert!(parse_field("quantity:=lots").is_err());
+        assert!(parse_field("tags:=[1]").is_err());
+
+        let cli = Cli::try_parse_from(["mech", "set", "1:buy/quantity", "100", "--json"]).unwrap();
+        assert!(matches!(cli.command, Commands::Set { json: true, .. }));
+    }
+
+    #[test]
+    fn dry_run_does_not_wait() {
+        let cli = Cli::try_parse_from(["mech", "use", "1:buy", "quantity=5", "--dry-run"]);
+        assert!(matches!(cli.unwrap().command, Commands::Use { dry_run: true, .. }));
+        assert!(Cli::try_parse_from(["mech", "use", "1:buy", "--dry-run", "-t", "5"]).is_err());
+    }
+
+    #[test]
+    fn find_matches_text_unless_asked_for_a_regex() {
+        let cli = Cli::try_parse_from(["mech", "find", "1", "price"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Find { regex: false, ignore_case: false, .. }
+        ));
+        let cli = Cli::try_parse_from(["mech", "find", "1:market", "^a", "-r", "-i"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Find { regex: true, ignore_case: true, .. }
+        ));
+    }
+
+    #[test]
+    fn wait_conditions_are_exclusive() {
+        let cli = Cli::try_parse_from(["mech", "wait", "1:order/status", "--equals", "filled"]);
+        assert!(matches!(
+            cli.unwrap().command,
+            Commands::Wait { equals: Some(_), changes: false, .. }
+        ));
+        assert!(
+            Cli::try_parse_from(["mech", "wait", "1:price", "--changes", "--matches", "^1"]).is_err()
+        );
+    }
+
+    #[test]
+    fn watch_updates_are_separated() {
+        let value = Node::from_value(json!({"price": "101.25"})).unwrap();
+        assert_eq!(
+            format_watch_update(&value, OutputFormat::Json, None, false),
+            "{\"price\":\"101.25\"}\n"
+        );
+        assert_eq!(
+            format_watch_update(&value, OutputFormat::Flat, Some("market/acrn"), false),
+            "market/acrn/price\t\"101.25\"\n\n"
+        );
+        assert!(format_watch_update(&value, OutputFormat::Yaml, None, false).starts_with("---\n"));
+
+        let control = Node::from_value(json!({"#": {"href": "/buy"}})).unwrap();
+        assert_eq!(
+            format_watch_update(&control, OutputFormat::Json, None, false),
+            "{\"#\":{\"href\":\"/buy\"}}\n"
+        );
+        assert_eq!(
+            format_watch_update(&control, OutputFormat::View, None, false),
+            "{\"#\":{\"type\":\"control\"}}\n"
+        );
+    }
+
     #[test]
     fn format_hypermap_simple() {
-        let value = json!({"price": 42});
+        let value = Node::from_value(json!({"price": 42})).unwrap();
         let output = format_hypermap_styled(&value, 0, false);
         assert_eq!(output, "price: 42\n");
     }
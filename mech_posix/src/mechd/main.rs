@@ -0,0 +1,1720 @@
+// Daemon for mech CLI
+//
+// This is the daemon binary (mechd). It listens on a Unix socket, processes
+// commands from the mech client, and keeps each tab's resource loaded on one
+// of two backends: Servo, a headless browser that runs the resource's
+// scripts, or the native backend, which speaks HyperMap over plain HTTP and
+// builds without Servo.
+
+use clap::Parser;
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use mech_cli::fetch::{FetchError, Request, Response};
+use mech_cli::{
+    cleanup, join_path, pid_path, read_message, socket_path, write_message, Backend, ControlInfo,
+    DaemonCall, DaemonCommand, DaemonError, DaemonReply, HistoryInfo, LoadState, MapNode, Match,
+    Node, Selector, TabInfo, WaitCheck, DEFAULT_TIMEOUT_MS,
+};
+
+mod native_backend;
+#[cfg(feature = "servo")]
+mod servo_backend;
+
+use native_backend::NativeTab;
+
+/// Servo when it is built in, since it runs resources' scripts.
+#[cfg(feature = "servo")]
+const DEFAULT_BACKEND: Backend = Backend::Servo;
+#[cfg(not(feature = "servo"))]
+const DEFAULT_BACKEND: Backend = Backend::Native;
+
+#[derive(Parser)]
+#[command(name = "mechd", about = "Mech daemon - loads and drives HyperMap tabs")]
+struct Cli {
+    /// Run in foreground (don't daemonize)
+    #[arg(short, long)]
+    foreground: bool,
+    /// Backend for tabs opened without choosing one
+    #[arg(short, long, value_enum, default_value_t = DEFAULT_BACKEND)]
+    backend: Backend,
+}
+
+/// How long a tab may report a complete load without `window.hypermap`
+/// before Open and Use give up and report the page's diagnostics.
+const HYPERMAP_INIT_GRACE: Duration = Duration::from_secs(1);
+
+/// How often pending Wait and Watch requests ask their tab whether the
+/// hypermap has mutated.
+const MUTATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The engine that loads a tab's resources and keeps its hypermap.
+///
+/// Methods that read the hypermap report through a callback, which Servo
+/// runs once its injected script has finished and the native backend runs
+/// straight away, so callers must not hold borrows the callback needs.
+trait TabBackend {
+    fn kind(&self) -> Backend;
+
+    /// The URL of the page being shown, or of the first page while it is
+    /// still loading.
+    fn url(&self) -> String;
+
+    fn load_state(&self) -> LoadState;
+
+    /// Number of loads that have finished, so that pending Open/Use replies
+    /// can tell a fresh page from the one that was showing when they were
+    /// issued.
+    fn completed_loads(&self) -> u64;
+
+    fn history(&self) -> History;
+
+    fn load(&self, url: Url);
+
+    /// Start a Back, Forward or Reload that the history allows.
+    fn traverse(&self, traversal: Traversal);
+
+    /// Fetch the whole hypermap, or a message saying why there is none.
+    fn query(&self, done: Box<dyn FnOnce(Result<Snapshot, String>)>);
+
+    /// Feed `value` into the field at `path`, as `Hypermap.input()` does,
+    /// then tell `done` whether there was a value there to change.
+    fn input(&self, path: &str, value: &Value, done: Inputted);
+
+    /// Fill in `data` below the control at `path`, then activate it as the
+    /// spec describes unless the page's scripts handle it. Nothing is
+    /// filled in unless `path` is a control and every field in `data` is a
+    /// value. Tells `sent` where the control's request went, or why the
+    /// control could not be used; only if it was used, tells `done` once any
+    /// request has been answered whether a new resource is loading, or why
+    /// not.
+    fn use_control(&self, path: &str, data: &HashMap<String, Value>, sent: Sent, done: Activated);
+
+    /// Report the hypermap's version, with the hypermap itself if that
+    /// differs from `seen`. `done` gets `None` if the page could not say.
+    fn probe_mutations(&self, seen: &str, done: Box<dyn FnOnce(Option<Mutations>)>);
+
+    /// Make progress on loads in flight; called on every turn of the main
+    /// loop.
+    fn spin(&self) {}
+}
+
+/// Told whether an input was made, or why not.
+type Inputted = Box<dyn FnOnce(Result<(), ControlError>)>;
+
+/// Told the URL a control's request was sent to once its data is filled
+/// in, `None` if the page's scripts handled it instead, or why the control
+/// could not be used.
+type Sent = Box<dyn FnOnce(Result<Option<Url>, ControlError>)>;
+
+/// Told whether activating a control loaded a new resource, or why its
+/// request failed.
+type Activated = Box<dyn FnOnce(Result<bool, ControlError>)>;
+
+/// Why an input or a control's activation failed. Paths are full ones from
+/// the root, so that a data field can be told apart from its control.
+enum ControlError {
+    PathNotFound(String),
+    NotAValue(String),
+    NotAControl(String),
+    /// The page threw an exception; holds its message.
+    #[cfg_attr(not(feature = "servo"), allow(dead_code))]
+    Script(String),
+    /// The control's request failed.
+    Request(FetchError),
+    /// Another load started before the control's request was answered.
+    Superseded,
+}
+
+impl ControlError {
+    /// How the error is reported to a client that called the tab `tab`.
+    fn into_daemon_error(self, tab: String) -> DaemonError {
+        match self {
+            ControlError::PathNotFound(path) => DaemonError::PathNotFound { tab, path },
+            ControlError::NotAValue(path) => DaemonError::NotAValue { tab, path },
+            ControlError::NotAControl(path) => DaemonError::NotAControl { tab, path },
+            ControlError::Script(message) => DaemonError::ScriptError { tab, message },
+            ControlError::Request(FetchError::InvalidUrl { url, reason }) => {
+                DaemonError::InvalidUrl { url, reason }
+            }
+            ControlError::Request(err) => DaemonError::PageError {
+                message: err.to_string(),
+            },
+            ControlError::Superseded => DaemonError::PageError {
+                message: "Superseded by a new load".to_string(),
+            },
+        }
+    }
+}
+
+/// A tab's hypermap, in the lossless form, and the URL it was loaded from.
+struct Snapshot {
+    url: String,
+    data: Value,
+}
+
+/// The answer to `TabBackend::probe_mutations`.
+struct Mutations {
+    version: String,
+    data: Option<Value>,
+}
+
+/// A request sent from its own thread, so the main loop never waits on the
+/// network.
+struct BackgroundFetch {
+    url: Url,
+    result: mpsc::Receiver<Result<Response, FetchError>>,
+}
+
+impl BackgroundFetch {
+    /// Send `request`, asking for the media types in `accept`.
+    fn start(request: Request, accept: &'static str) -> Self {
+        let url = request.url.clone();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(mech_cli::fetch::fetch_accepting(&request, accept));
+        });
+        BackgroundFetch { url, result: rx }
+    }
+
+    /// The outcome, once the request has been answered.
+    fn poll(&self) -> Option<Result<Response, FetchError>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(FetchError::Network {
+                url: self.url.to_string(),
+                reason: "the request was abandoned".to_string(),
+            })),
+        }
+    }
+}
+
+/// Check that there is a value at `path` for an input to change.
+fn check_field(root: &Node, path: &str) -> Result<(), ControlError> {
+    match root.at_path(path) {
+        None => Err(ControlError::PathNotFound(path.to_string())),
+        Some(node) if node.as_value().is_none() => Err(ControlError::NotAValue(path.to_string())),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Feed `value` into the field at `path`, if there is one.
+fn input_field(root: &mut Node, path: &str, value: &Value) -> Result<(), ControlError> {
+    check_field(root, path)?;
+    root.input(path, value.clone());
+    Ok(())
+}
+
+/// Fill in `data` below the control at `path`, as Use does before
+/// activating it, and return the control. Nothing changes unless `path` is
+/// a control and every field in `data` is a value.
+fn fill_control<'a>(
+    root: &'a mut Node,
+    path: &str,
+    data: &HashMap<String, Value>,
+) -> Result<&'a MapNode, ControlError> {
+    match root.at_path(path) {
+        None => return Err(ControlError::PathNotFound(path.to_string())),
+        Some(node) if !node.is_control() => {
+            return Err(ControlError::NotAControl(path.to_string()));
+        }
+        Some(_) => {}
+    }
+    for key in data.keys() {
+        check_field(root, &join_path(path, key))?;
+    }
+    for (key, value) in data {
+        root.input(&join_path(path, key), value.clone());
+    }
+    Ok(root.at_path(path).and_then(Node::as_map).expect("checked to be a control"))
+}
+
+/// The request activating the control at `path` in `snapshot` makes once
+/// `data` has been filled in below it, as Use would make it.
+fn preview_request(
+    snapshot: Snapshot,
+    tab: &str,
+    path: &str,
+    data: &HashMap<String, Value>,
+) -> Result<Request, DaemonError> {
+    let base = Url::parse(&snapshot.url).map_err(|e| DaemonError::PageError {
+        message: format!("invalid page URL: {}", e),
+    })?;
+    let mut node = Node::from_value(snapshot.data).map_err(|e| DaemonError::PageError {
+        message: e.to_string(),
+    })?;
+    fill_control(&mut node, path, data)
+        .and_then(|control| Request::for_control(control, &base).map_err(ControlError::Request))
+        .map_err(|err| err.into_daemon_error(tab.to_string()))
+}
+
+/// A tab managed by the daemon
+struct Tab {
+    /// Assigned when the tab is created and never reused, see
+    /// `DaemonState::tab_counter`.
+    id: usize,
+    name: Option<String>,
+    backend: Box<dyn TabBackend>,
+    /// Values written with Set and Use, replayed into forks of this tab.
+    /// Shared with the callbacks that record them once they are made.
+    inputs: Rc<RefCell<Vec<RecordedInput>>>,
+}
+
+/// A tab's session history as last reported by its backend.
+#[derive(Clone, Default)]
+struct History {
+    entries: Vec<Url>,
+    current: usize,
+}
+
+#[derive(Clone)]
+struct RecordedInput {
+    /// `TabBackend::completed_loads` when the input was made, so inputs to
+    /// pages that have since been navigated away from can be told apart.
+    load: u64,
+    path: String,
+    value: Value,
+}
+
+/// Create a tab on `backend`, loading `url`.
+fn create_tab(
+    #[cfg_attr(not(feature = "servo"), allow(unused_variables))] state: &mut DaemonState,
+    backend: Backend,
+    id: usize,
+    url: Url,
+    name: Option<String>,
+) -> Result<Tab, DaemonError> {
+    let backend: Box<dyn TabBackend> = match backend {
+        Backend::Native => Box::new(NativeTab::new(url)),
+        #[cfg(feature = "servo")]
+        Backend::Servo => {
+            // Only started once needed, so native-only sessions never pay for it
+            let servo = state.servo.get_or_insert_with(servo_backend::start);
+            Box::new(servo_backend::ServoTab::new(servo, url)?)
+        }
+        #[cfg(not(feature = "servo"))]
+        Backend::Servo => return Err(DaemonError::BackendUnavailable { backend }),
+    };
+    Ok(Tab {
+        id,
+        name,
+        backend,
+        inputs: Rc::new(RefCell::new(Vec::new())),
+    })
+}
+
+impl Tab {
+    fn info(&self) -> TabInfo {
+        TabInfo {
+            id: self.id,
+            name: self.name.clone(),
+            url: self.current_url(),
+            state: self.backend.load_state(),
+            backend: self.backend.kind(),
+        }
+    }
+
+    /// Feed `value` into the page, recording it so forks of this tab can
+    /// replay it if it was made.
+    fn input(&self, path: String, value: Value, done: Inputted) {
+        let inputs = self.inputs.clone();
+        let load = self.backend.completed_loads();
+        self.backend.input(
+            &path.clone(),
+            &value.clone(),
+            Box::new(move |result| {
+                if result.is_ok() {
+                    inputs.borrow_mut().push(RecordedInput { load, path, value });
+                }
+                done(result);
+            }),
+        );
+    }
+
+    /// Fill in `data` and activate the control at `path`, recording the
+    /// data like `input` does if it was filled in.
+    fn use_control(&self, path: String, data: HashMap<String, Value>, sent: Sent, done: Activated) {
+        let inputs = self.inputs.clone();
+        let load = self.backend.completed_loads();
+        self.backend.use_control(
+            &path.clone(),
+            &data.clone(),
+            Box::new(move |result| {
+                if matches!(result, Ok(_) | Err(ControlError::Request(_))) {
+                    inputs
+                        .borrow_mut()
+                        .extend(data.into_iter().map(|(key, value)| RecordedInput {
+                            load,
+                            path: join_path(&path, &key),
+                            value,
+                        }));
+                }
+                sent(result);
+            }),
+            done,
+        );
+    }
+
+    /// Inputs made to the page that is showing now.
+    fn current_inputs(&self) -> Vec<RecordedInput> {
+        let load = self.backend.completed_loads();
+        self.inputs
+            .borrow()
+            .iter()
+            .filter(|input| input.load == load)
+            .cloned()
+            .collect()
+    }
+
+    fn current_url(&self) -> String {
+        self.backend.url()
+    }
+}
+
+/// State shared across the daemon
+struct DaemonState {
+    /// Backend for tabs opened without choosing one.
+    backend: Backend,
+    /// Started when the first Servo tab is opened.
+    #[cfg(feature = "servo")]
+    servo: Option<servo::Servo>,
+    tabs: Vec<Tab>,
+    /// Highest tab ID handed out so far. IDs are never reused, so closing a
+    /// tab leaves the IDs of the others unchanged.
+    tab_counter: usize,
+    pending_loads: Vec<PendingLoad>,
+    pending_waits: Vec<PendingWait>,
+    watches: Vec<ActiveWatch>,
+}
+
+/// A reply held back until a tab has finished loading a hypermap.
+struct PendingLoad {
+    tab_id: usize,
+    tab: String,
+    /// Reply once the tab has completed more loads than this.
+    completed_loads: u64,
+    /// Whether a navigation is expected at all. `None` until the backend has
+    /// said whether it started one, or why it could not.
+    navigating: Rc<RefCell<Option<Result<bool, DaemonError>>>>,
+    probe: Rc<RefCell<LoadProbe>>,
+    /// When the probe first found the page loaded without a hypermap.
+    missing_since: Cell<Option<Instant>>,
+    deadline: Instant,
+    timeout_ms: u64,
+    reply: Box<dyn FnOnce(TabInfo) -> DaemonReply>,
+    response_tx: mpsc::Sender<DaemonReply>,
+    /// History entries still to load, in order, when rebuilding a fork.
+    remaining: VecDeque<Url>,
+    /// Inputs to apply once the last page is ready, when rebuilding a fork.
+    replay: Vec<RecordedInput>,
+}
+
+/// Progress of the hypermap check run once a load completes.
+enum LoadProbe {
+    Idle,
+    Running,
+    /// The hypermap is initialized; holds the page's final URL.
+    Ready(String),
+    /// The page loaded without a hypermap; holds the diagnostic message.
+    /// Retried until `HYPERMAP_INIT_GRACE` has passed on backends whose
+    /// scripts may still be starting up.
+    Missing(String),
+}
+
+/// Fetches a tab's hypermap each time its backend reports a mutation.
+struct MutationProbe {
+    /// Version at which the hypermap was last fetched.
+    seen: String,
+    state: Rc<RefCell<MutationProbeState>>,
+    next_probe: Instant,
+}
+
+enum MutationProbeState {
+    Idle,
+    Running,
+    Done(Option<Mutations>),
+}
+
+/// A Wait that has not yet seen its condition hold.
+struct PendingWait {
+    tab_id: usize,
+    tab: String,
+    path: String,
+    check: WaitCheck,
+    /// Whether the condition has been evaluated at least once.
+    checked: bool,
+    probe: MutationProbe,
+    deadline: Instant,
+    timeout_ms: u64,
+    response_tx: mpsc::Sender<DaemonReply>,
+}
+
+/// A Watch streaming snapshots of a path to its client.
+struct ActiveWatch {
+    tab_id: usize,
+    tab: String,
+    path: Option<String>,
+    /// The last snapshot sent, so unrelated mutations are not repeated.
+    last: Option<Value>,
+    probe: MutationProbe,
+    response_tx: mpsc::Sender<DaemonReply>,
+    /// The client's socket, so a client that hangs up is noticed even while
+    /// there is nothing new to send it.
+    client: Option<UnixStream>,
+}
+
+/// A command from a connection thread, with the channel its replies go back
+/// on and, for a Watch, a handle on the client's socket.
+type Incoming = (DaemonCommand, mpsc::Sender<DaemonReply>, Option<UnixStream>);
+
+fn main() {
+    let cli = Cli::parse();
+    #[cfg(not(feature = "servo"))]
+    if cli.backend == Backend::Servo {
+        eprintln!("mechd was built without the servo backend");
+        process::exit(1);
+    }
+    start_daemon(cli.foreground, cli.backend);
+}
+
+fn start_daemon(foreground: bool, backend: Backend) {
+    // Check if already running
+    if UnixStream::connect(socket_path()).is_ok() {
+        eprintln!("Daemon already running");
+        process::exit(1);
+    }
+
+    // Clean up stale files
+    let _ = fs::remove_file(socket_path());
+    let _ = fs::remove_file(pid_path());
+
+    if !foreground {
+        // Daemonize: fork and let parent exit
+        // This gives clean shell output (no background job notification)
+        match unsafe { libc::fork() } {
+            -1 => {
+                eprintln!("Failed to fork");
+                process::exit(1);
+            }
+            0 => {
+                // Child process - continue as daemon
+            }
+            _ => {
+                // Parent process - exit cleanly
+                // Small delay to let child set up socket before parent exits
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                return;
+            }
+        }
+
+        // Create new session, detach from controlling terminal
+        unsafe {
+            libc::setsid();
+        }
+    }
+
+    // Write PID file (now with daemon's actual PID)
+    fs::write(pid_path(), process::id().to_string()).expect("Failed to write PID file");
+
+    // Redirect stdout/stderr - use log file if MECH_LOG is set, otherwise /dev/null
+    // In foreground mode, don't redirect unless MECH_LOG is set
+    let log_path = std::env::var("MECH_LOG").ok();
+    let log_target = if foreground && log_path.is_none() {
+        None // Keep stdout/stderr in foreground mode
+    } else {
+        log_path
+            .as_ref()
+            .and_then(|p| File::options().create(true).append(true).open(p).ok())
+            .or_else(|| File::options().write(true).open("/dev/null").ok())
+    };
+
+    if let Some(log_file) = log_target {
+        let log_fd = log_file.as_raw_fd();
+        unsafe {
+            libc::dup2(log_fd, libc::STDOUT_FILENO);
+            libc::dup2(log_fd, libc::STDERR_FILENO);
+        }
+        // Keep file open
+        std::mem::forget(log_file);
+    }
+
+    let state = Rc::new(RefCell::new(DaemonState {
+        backend,
+        #[cfg(feature = "servo")]
+        servo: None,
+        tabs: Vec::new(),
+        tab_counter: 0,
+        pending_loads: Vec::new(),
+        pending_waits: Vec::new(),
+        watches: Vec::new(),
+    }));
+
+    // Start socket listener thread
+    let (cmd_tx, cmd_rx) = mpsc::channel::<Incoming>();
+    std::thread::spawn(move || {
+        socket_listener(cmd_tx);
+    });
+
+    // Set up panic hook to log panics before crashing
+    std::panic::set_hook(Box::new(|info| {
+        eprintln!("Daemon panic: {}", info);
+    }));
+
+    // Main event loop
+    loop {
+        // Process any pending commands
+        while let Ok((cmd, response_tx, client)) = cmd_rx.try_recv() {
+            handle_command(&state, cmd, response_tx, client);
+        }
+
+        // Answer Open/Use requests whose page has finished loading and Wait
+        // requests whose condition now holds, and stream Watch updates
+        poll_pending_loads(&state);
+        poll_pending_waits(&state);
+        poll_watches(&state);
+
+        // Spin Servo's event loop to process rendering/JS, and let native
+        // tabs pick up finished requests
+        {
+            let state_ref = state.borrow();
+            #[cfg(feature = "servo")]
+            if let Some(servo) = &state_ref.servo {
+                servo.spin_event_loop();
+            }
+            for tab in &state_ref.tabs {
+                tab.backend.spin();
+            }
+        }
+
+        // Small sleep to avoid busy-waiting
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+fn socket_listener(cmd_tx: mpsc::Sender<Incoming>) {
+    let listener = UnixListener::bind(socket_path()).expect("Failed to bind socket");
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // Replies may wait for a page load, so each connection gets
+                // its own thread rather than holding up other clients.
+                let cmd_tx = cmd_tx.clone();
+                std::thread::spawn(move || handle_connection(stream, cmd_tx));
+            }
+            Err(e) => eprintln!("Socket error: {}", e),
+        }
+    }
+}
+
+fn handle_connection(mut stream: UnixStream, cmd_tx: mpsc::Sender<Incoming>) {
+    let msg = match read_message(&mut stream) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Failed to read message: {}", e);
+            return;
+        }
+    };
+
+    let call: DaemonCall = match serde_json::from_slice(&msg) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid command: {}", e);
+            let reply = DaemonReply::Err(DaemonError::PageError {
+                message: format!("Protocol error: {}. Is mechd up to date?", e),
+            });
+            if let Ok(json) = serde_json::to_vec(&reply) {
+                let _ = write_message(&mut stream, &json);
+            }
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+            return;
+        }
+    };
+
+    // Streaming methods can only answer callers that accept several replies
+    if matches!(call.command, DaemonCommand::Watch { .. }) && !call.more {
+        let reply = DaemonReply::Err(DaemonError::ExpectedMore);
+        if let Ok(json) = serde_json::to_vec(&reply) {
+            let _ = write_message(&mut stream, &json);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+        return;
+    }
+
+    let client = match call.command {
+        DaemonCommand::Watch { .. } => stream.try_clone().ok(),
+        _ => None,
+    };
+    let (tx, rx) = mpsc::channel();
+    let _ = cmd_tx.send((call.command, tx, client));
+
+    // Forward replies until one without `continues`, or until the main loop
+    // drops a Watch whose client has hung up.
+    for reply in rx {
+        let Ok(json) = serde_json::to_vec(&reply) else {
+            break;
+        };
+        if write_message(&mut stream, &json).is_err() || !reply.continues() {
+            break;
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+}
+
+fn handle_command(
+    state: &Rc<RefCell<DaemonState>>,
+    cmd: DaemonCommand,
+    response_tx: mpsc::Sender<DaemonReply>,
+    client: Option<UnixStream>,
+) {
+    let mut state_ref = state.borrow_mut();
+
+    match cmd {
+        DaemonCommand::Open {
+            url,
+            name,
+            backend,
+            timeout_ms,
+        } => {
+            if let Some(ref n) = name
+                && state_ref.tabs.iter().any(|t| t.name.as_deref() == Some(n))
+            {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::NameInUse {
+                    name: n.clone(),
+                }));
+                return;
+            }
+
+            let full_url = if url.starts_with("http://") || url.starts_with("https://") {
+                url.clone()
+            } else {
+                format!("https://{}", url)
+            };
+
+            let target = match Url::parse(&full_url) {
+                Ok(u) => u,
+                Err(e) => {
+                    let _ = response_tx.send(DaemonReply::Err(DaemonError::InvalidUrl {
+                        url: full_url,
+                        reason: format!("{:?}", e),
+                    }));
+                    return;
+                }
+            };
+
+            let backend = backend.unwrap_or(state_ref.backend);
+            let tab_id = state_ref.tab_counter + 1;
+            let tab = match create_tab(&mut state_ref, backend, tab_id, target, name.clone()) {
+                Ok(tab) => tab,
+                Err(err) => {
+                    let _ = response_tx.send(DaemonReply::Err(err));
+                    return;
+                }
+            };
+
+            state_ref.tab_counter = tab_id;
+            state_ref.tabs.push(tab);
+
+            let display_name = name
+                .as_ref()
+                .map(|n| format!("{} ({})", tab_id, n))
+                .unwrap_or_else(|| tab_id.to_string());
+            let timeout_ms = timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+            if timeout_ms == 0 {
+                let _ = response_tx.send(DaemonReply::ok_tab(
+                    format!("Opened tab {} at {}\n", display_name, url),
+                    TabInfo {
+                        id: tab_id,
+                        name,
+                        url: full_url,
+                        state: LoadState::Loading,
+                        backend,
+                    },
+                ));
+                return;
+            }
+
+            state_ref.pending_loads.push(PendingLoad::new(
+                tab_id,
+                tab_id.to_string(),
+                0,
+                timeout_ms,
+                response_tx,
+                Box::new(move |info| {
+                    DaemonReply::ok_tab(
+                        format!("Opened tab {} at {}\n", display_name, info.url),
+                        TabInfo {
+                            id: tab_id,
+                            ..info
+                        },
+                    )
+                }),
+            ));
+        }
+
+        DaemonCommand::Show { tab, path } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                state_ref.tabs[idx].backend.query(Box::new(move |result| {
+                    let reply = match result {
+                        Ok(snapshot) => {
+                            let value = match path {
+                                Some(ref p) => get_value_at_path(snapshot.data, p, &tab),
+                                None => Ok(Some(snapshot.data)),
+                            };
+                            match value {
+                                Ok(Some(v)) => DaemonReply::ok_snapshot(v, snapshot.url),
+                                Ok(None) => DaemonReply::Err(DaemonError::PathNotFound {
+                                    tab,
+                                    path: path.unwrap_or_default(),
+                                }),
+                                Err(err) => DaemonReply::Err(err),
+                            }
+                        }
+                        Err(message) => DaemonReply::Err(DaemonError::PageError { message }),
+                    };
+                    let _ = response_tx.send(reply);
+                }));
+                // Response will be sent by the callback above.
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Select { tab, selector } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                match Selector::parse(&selector) {
+                    Ok(parsed) => state_ref.tabs[idx].backend.query(Box::new(move |result| {
+                        let root = result.and_then(|snapshot| {
+                            Node::from_value(snapshot.data).map_err(|e| e.to_string())
+                        });
+                        let reply = match root {
+                            Ok(root) => {
+                                let matches = parsed.select(&root).into_iter();
+                                let matches = matches.map(|(path, node)| Match {
+                                    path,
+                                    hypermap: node.to_json(),
+                                });
+                                DaemonReply::ok_matches(matches.collect())
+                            }
+                            Err(message) => DaemonReply::Err(DaemonError::PageError { message }),
+                        };
+                        let _ = response_tx.send(reply);
+                    })),
+                    Err(e) => {
+                        let _ = response_tx.send(DaemonReply::Err(DaemonError::InvalidSelector {
+                            selector,
+                            reason: e.reason,
+                        }));
+                    }
+                }
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Controls { tab, method } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                state_ref.tabs[idx].backend.query(Box::new(move |result| {
+                    let reply = match result {
+                        Ok(snapshot) => {
+                            let listed = Url::parse(&snapshot.url)
+                                .map_err(|e| format!("invalid page URL: {}", e))
+                                .and_then(|base| {
+                                    let node = Node::from_value(snapshot.data)
+                                        .map_err(|e| e.to_string())?;
+                                    Ok(ControlInfo::list(&node, &base, method.as_deref()))
+                                });
+                            match listed {
+                                Ok(controls) => DaemonReply::ok_controls(controls),
+                                Err(message) => {
+                                    DaemonReply::Err(DaemonError::PageError { message })
+                                }
+                            }
+                        }
+                        Err(message) => DaemonReply::Err(DaemonError::PageError { message }),
+                    };
+                    let _ = response_tx.send(reply);
+                }));
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Set { tab, path, value } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                state_ref.tabs[idx].input(
+                    path,
+                    value,
+                    Box::new(move |result| {
+                        let reply = match result {
+                            Ok(()) => DaemonReply::ok(),
+                            Err(err) => DaemonReply::Err(err.into_daemon_error(tab)),
+                        };
+                        let _ = response_tx.send(reply);
+                    }),
+                );
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Use {
+            tab,
+            path,
+            data,
+            timeout_ms,
+        } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                let tab_data = &state_ref.tabs[idx];
+
+                let timeout_ms = timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+                if timeout_ms == 0 {
+                    // Still report a control that could not be used, but
+                    // not wait for its request to be answered
+                    let url = tab_data.current_url();
+                    tab_data.use_control(
+                        path,
+                        data,
+                        Box::new(move |result| {
+                            let reply = match result {
+                                Ok(target) => {
+                                    DaemonReply::ok_url(target.map_or(url, String::from))
+                                }
+                                Err(err) => DaemonReply::Err(err.into_daemon_error(tab)),
+                            };
+                            let _ = response_tx.send(reply);
+                        }),
+                        Box::new(|_| {}),
+                    );
+                    return;
+                }
+
+                let pending = PendingLoad::new(
+                    tab_data.id,
+                    tab.clone(),
+                    tab_data.backend.completed_loads(),
+                    timeout_ms,
+                    response_tx,
+                    Box::new(|info| DaemonReply::ok_url(info.url)),
+                );
+                *pending.navigating.borrow_mut() = None;
+                let navigating = pending.navigating.clone();
+                let failed = pending.navigating.clone();
+                let failed_tab = tab.clone();
+                tab_data.use_control(
+                    path,
+                    data,
+                    Box::new(move |result| {
+                        if let Err(err) = result {
+                            *failed.borrow_mut() = Some(Err(err.into_daemon_error(failed_tab)));
+                        }
+                    }),
+                    Box::new(move |result| {
+                        let loading = result.map_err(|err| err.into_daemon_error(tab));
+                        *navigating.borrow_mut() = Some(loading);
+                    }),
+                );
+                state_ref.pending_loads.push(pending);
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Preview { tab, path, data } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                state_ref.tabs[idx].backend.query(Box::new(move |result| {
+                    let reply = match result {
+                        Ok(snapshot) => match preview_request(snapshot, &tab, &path, &data) {
+                            Ok(request) => DaemonReply::ok_request(request.into()),
+                            Err(err) => DaemonReply::Err(err),
+                        },
+                        Err(message) => DaemonReply::Err(DaemonError::PageError { message }),
+                    };
+                    let _ = response_tx.send(reply);
+                }));
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Wait {
+            tab,
+            path,
+            condition,
+            timeout_ms,
+        } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                match WaitCheck::new(condition) {
+                    Ok(check) => {
+                        let tab_id = state_ref.tabs[idx].id;
+                        let timeout_ms = timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+                        state_ref.pending_waits.push(PendingWait {
+                            tab_id,
+                            tab,
+                            path,
+                            check,
+                            checked: false,
+                            probe: MutationProbe::new(),
+                            deadline: Instant::now() + Duration::from_millis(timeout_ms),
+                            timeout_ms,
+                            response_tx,
+                        });
+                    }
+                    Err(err) => {
+                        let _ = response_tx.send(DaemonReply::Err(err));
+                    }
+                }
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Watch { tab, path } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                let tab_id = state_ref.tabs[idx].id;
+                state_ref.watches.push(ActiveWatch {
+                    tab_id,
+                    tab,
+                    path,
+                    last: None,
+                    probe: MutationProbe::new(),
+                    response_tx,
+                    client,
+                });
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Fork {
+            tab,
+            name,
+            timeout_ms,
+        } => {
+            if let Some(ref n) = name
+                && state_ref.tabs.iter().any(|t| t.name.as_deref() == Some(n))
+            {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::NameInUse {
+                    name: n.clone(),
+                }));
+                return;
+            }
+
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                let source = &state_ref.tabs[idx];
+
+                // Rebuild the history up to the current entry by loading each
+                // page in turn, then replay the inputs made to the last one.
+                let mut remaining: VecDeque<Url> = {
+                    let history = source.backend.history();
+                    history.entries[..(history.current + 1).min(history.entries.len())]
+                        .iter()
+                        .cloned()
+                        .collect()
+                };
+                let first_url = match remaining.pop_front() {
+                    Some(url) => url,
+                    None => match Url::parse(&source.current_url()) {
+                        Ok(u) => u,
+                        Err(e) => {
+                            let _ = response_tx.send(DaemonReply::Err(DaemonError::InvalidUrl {
+                                url: source.current_url(),
+                                reason: format!("{:?}", e),
+                            }));
+                            return;
+                        }
+                    },
+                };
+                let source_url = source.current_url();
+                let replay = source.current_inputs();
+                let backend = source.backend.kind();
+
+                let tab_id = state_ref.tab_counter + 1;
+                let new_tab =
+                    match create_tab(&mut state_ref, backend, tab_id, first_url, name.clone()) {
+                        Ok(tab) => tab,
+                        Err(err) => {
+                            let _ = response_tx.send(DaemonReply::Err(err));
+                            return;
+                        }
+                    };
+
+                state_ref.tab_counter = tab_id;
+                state_ref.tabs.push(new_tab);
+
+                let display_name = name
+                    .as_ref()
+                    .map(|n| format!("{} ({})", tab_id, n))
+                    .unwrap_or_else(|| tab_id.to_string());
+                let timeout_ms = timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+                let (response_tx, timeout_ms) = if timeout_ms == 0 {
+                    let _ = response_tx.send(DaemonReply::ok_tab(
+                        format!("Forked to tab {}\n", display_name),
+                        TabInfo {
+                            id: tab_id,
+                            name,
+                            url: source_url,
+                            state: LoadState::Loading,
+                            backend,
+                        },
+                    ));
+                    // Still restore the state, with nobody waiting on the result
+                    (mpsc::channel().0, DEFAULT_TIMEOUT_MS)
+                } else {
+                    (response_tx, timeout_ms)
+                };
+
+                let mut pending = PendingLoad::new(
+                    tab_id,
+                    tab_id.to_string(),
+                    0,
+                    timeout_ms,
+                    response_tx,
+                    Box::new(move |info| {
+                        DaemonReply::ok_tab(
+                            format!("Forked to tab {}\n", display_name),
+                            TabInfo {
+                                id: tab_id,
+                                ..info
+                            },
+                        )
+                    }),
+                );
+                pending.remaining = remaining;
+                pending.replay = replay;
+                state_ref.pending_loads.push(pending);
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Close { tab } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                state_ref.tabs.remove(idx);
+                let _ = response_tx.send(DaemonReply::ok_message(format!(
+                    "Closed tab '{}'\n",
+                    tab
+                )));
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Name { tab, name } => {
+            if state_ref
+                .tabs
+                .iter()
+                .any(|t| t.name.as_deref() == Some(&name))
+            {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::NameInUse { name }));
+                return;
+            }
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                state_ref.tabs[idx].name = Some(name.clone());
+                let _ = response_tx.send(DaemonReply::ok_message(format!(
+                    "Tab {} renamed to '{}'\n",
+                    state_ref.tabs[idx].id,
+                    name
+                )));
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Goto {
+            tab,
+            url,
+            timeout_ms,
+        } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                let tab_data = &state_ref.tabs[idx];
+
+                let base = tab_data.current_url();
+                let target = match Url::parse(&base).and_then(|b| b.join(&url)) {
+                    Ok(u) => u,
+                    Err(e) => {
+                        let _ = response_tx.send(DaemonReply::Err(DaemonError::InvalidUrl {
+                            url,
+                            reason: format!("{:?}", e),
+                        }));
+                        return;
+                    }
+                };
+
+                let tab_id = tab_data.id;
+                let completed_loads = tab_data.backend.completed_loads();
+                tab_data.backend.load(target.clone());
+
+                let timeout_ms = timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+                if timeout_ms == 0 {
+                    let _ = response_tx.send(DaemonReply::ok_url(target));
+                    return;
+                }
+                state_ref.pending_loads.push(PendingLoad::new(
+                    tab_id,
+                    tab,
+                    completed_loads,
+                    timeout_ms,
+                    response_tx,
+                    Box::new(|info| DaemonReply::ok_url(info.url)),
+                ));
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Back { tab, timeout_ms } => {
+            traverse(&mut state_ref, tab, Traversal::Back, timeout_ms, response_tx);
+        }
+
+        DaemonCommand::Forward { tab, timeout_ms } => {
+            traverse(&mut state_ref, tab, Traversal::Forward, timeout_ms, response_tx);
+        }
+
+        DaemonCommand::Reload { tab, timeout_ms } => {
+            traverse(&mut state_ref, tab, Traversal::Reload, timeout_ms, response_tx);
+        }
+
+        DaemonCommand::History { tab } => {
+            if let Some(idx) = resolve_tab(&state_ref.tabs, &tab) {
+                let history = state_ref.tabs[idx].backend.history();
+                let _ = response_tx.send(DaemonReply::ok_history(HistoryInfo {
+                    entries: history.entries.iter().map(|u| u.to_string()).collect(),
+                    current: history.current,
+                }));
+            } else {
+                let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+            }
+        }
+
+        DaemonCommand::Tabs => {
+            let tabs = state_ref
+                .tabs
+                .iter()
+                .map(Tab::info)
+                .collect();
+            let _ = response_tx.send(DaemonReply::ok_tabs(tabs));
+        }
+
+        DaemonCommand::Shutdown => {
+            let _ = response_tx.send(DaemonReply::ok());
+            cleanup();
+            process::exit(0);
+        }
+    }
+}
+
+impl PendingLoad {
+    fn new(
+        tab_id: usize,
+        tab: String,
+        completed_loads: u64,
+        timeout_ms: u64,
+        response_tx: mpsc::Sender<DaemonReply>,
+        reply: Box<dyn FnOnce(TabInfo) -> DaemonReply>,
+    ) -> Self {
+        PendingLoad {
+            tab_id,
+            tab,
+            completed_loads,
+            navigating: Rc::new(RefCell::new(Some(Ok(true)))),
+            probe: Rc::new(RefCell::new(LoadProbe::Idle)),
+            missing_since: Cell::new(None),
+            deadline: Instant::now() + Duration::from_millis(timeout_ms),
+            timeout_ms,
+            reply,
+            response_tx,
+            remaining: VecDeque::new(),
+            replay: Vec::new(),
+        }
+    }
+
+    /// Advance the wait by one step, returning the outcome once it is settled.
+    fn poll(&mut self, tabs: &[Tab]) -> Option<Result<TabInfo, DaemonError>> {
+        let Some(idx) = tabs.iter().position(|t| t.id == self.tab_id) else {
+            return Some(Err(DaemonError::TabNotFound {
+                tab: self.tab.clone(),
+            }));
+        };
+        let tab = &tabs[idx];
+
+        match &*self.navigating.borrow() {
+            // The control was handled without leaving the page
+            Some(Ok(false)) => return Some(Ok(self.loaded_info(tab))),
+            Some(Err(err)) => return Some(Err(err.clone())),
+            None => return self.timed_out(),
+            Some(Ok(true)) => {}
+        }
+
+        if tab.backend.completed_loads() <= self.completed_loads
+            || tab.backend.load_state() != LoadState::Complete
+        {
+            return self.timed_out();
+        }
+
+        if let Some(url) = self.remaining.pop_front() {
+            self.completed_loads = tab.backend.completed_loads();
+            tab.backend.load(url);
+            return self.timed_out();
+        }
+
+        let mut probe = self.probe.borrow_mut();
+        match &*probe {
+            LoadProbe::Idle => {
+                *probe = LoadProbe::Running;
+                // The native backend answers straight away
+                drop(probe);
+                let probe_handle = self.probe.clone();
+                tab.backend.query(Box::new(move |result| {
+                    *probe_handle.borrow_mut() = match result {
+                        Ok(snapshot) => LoadProbe::Ready(snapshot.url),
+                        Err(message) => LoadProbe::Missing(message),
+                    };
+                }));
+                None
+            }
+            LoadProbe::Running => self.timed_out(),
+            LoadProbe::Ready(_) => {
+                drop(probe);
+                Some(Ok(self.loaded_info(tab)))
+            }
+            LoadProbe::Missing(message) => {
+                let since = self.missing_since.get().unwrap_or_else(Instant::now);
+                self.missing_since.set(Some(since));
+                // Only a page's scripts can still bring the hypermap up
+                if tab.backend.kind() == Backend::Native
+                    || since.elapsed() >= HYPERMAP_INIT_GRACE
+                    || Instant::now() >= self.deadline
+                {
+                    return Some(Err(DaemonError::PageError {
+                        message: message.clone(),
+                    }));
+                }
+                // Scripts may still be initializing; check again
+                *probe = LoadProbe::Idle;
+                None
+            }
+        }
+    }
+
+    fn loaded_info(&self, tab: &Tab) -> TabInfo {
+        let mut info = tab.info();
+        if let LoadProbe::Ready(url) = &*self.probe.borrow()
+            && !url.is_empty()
+        {
+            info.url = url.clone();
+        }
+        info
+    }
+
+    fn timed_out<T>(&self) -> Option<Result<T, DaemonError>> {
+        (Instant::now() >= self.deadline).then(|| {
+            Err(DaemonError::Timeout {
+                tab: self.tab.clone(),
+                timeout_ms: self.timeout_ms,
+            })
+        })
+    }
+}
+
+/// How Back, Forward and Reload move a tab through its history.
+#[derive(Clone, Copy)]
+enum Traversal {
+    Back,
+    Forward,
+    Reload,
+}
+
+/// Start a Back, Forward or Reload and reply once the page has loaded.
+fn traverse(
+    state: &mut DaemonState,
+    tab: String,
+    traversal: Traversal,
+    timeout_ms: Option<u64>,
+    response_tx: mpsc::Sender<DaemonReply>,
+) {
+    let Some(idx) = resolve_tab(&state.tabs, &tab) else {
+        let _ = response_tx.send(DaemonReply::Err(DaemonError::TabNotFound { tab }));
+        return;
+    };
+    let tab_data = &state.tabs[idx];
+    let history = tab_data.backend.history();
+
+    let (can_move, direction, offset) = match traversal {
+        Traversal::Back => (history.current > 0, "back", -1),
+        Traversal::Forward => (history.current + 1 < history.entries.len(), "forward", 1),
+        Traversal::Reload => (true, "", 0),
+    };
+    if !can_move {
+        let _ = response_tx.send(DaemonReply::Err(DaemonError::NoHistoryEntry {
+            tab,
+            direction: direction.to_string(),
+        }));
+        return;
+    }
+
+    let target_url = history
+        .current
+        .checked_add_signed(offset)
+        .and_then(|i| history.entries.get(i))
+        .map(|u| u.to_string())
+        .unwrap_or_else(|| tab_data.current_url());
+    let tab_id = tab_data.id;
+    let completed_loads = tab_data.backend.completed_loads();
+    tab_data.backend.traverse(traversal);
+
+    let timeout_ms = timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+    if timeout_ms == 0 {
+        let _ = response_tx.send(DaemonReply::ok_url(target_url));
+        return;
+    }
+    state.pending_loads.push(PendingLoad::new(
+        tab_id,
+        tab,
+        completed_loads,
+        timeout_ms,
+        response_tx,
+        Box::new(|info| DaemonReply::ok_url(info.url)),
+    ));
+}
+
+/// Reply to every command waiting on a page load whose wait has settled.
+fn poll_pending_loads(state: &Rc<RefCell<DaemonState>>) {
+    let mut state_ref = state.borrow_mut();
+    let state_ref = &mut *state_ref;
+    let mut i = 0;
+    while i < state_ref.pending_loads.len() {
+        let Some(outcome) = state_ref.pending_loads[i].poll(&state_ref.tabs) else {
+            i += 1;
+            continue;
+        };
+        let pending = state_ref.pending_loads.swap_remove(i);
+        let reply = match outcome {
+            Ok(info) => {
+                let tab = state_ref.tabs.iter().find(|t| t.id == pending.tab_id);
+                match tab {
+                    Some(tab) if !pending.replay.is_empty() => {
+                        let reply = pending.reply;
+                        replay_inputs(tab, pending.replay, move |failed| {
+                            let mut reply = reply(info);
+                            if let DaemonReply::Ok { parameters, .. } = &mut reply {
+                                for message in failed {
+                                    let line = format!("Could not restore a value: {}\n", message);
+                                    parameters.message.push_str(&line);
+                                }
+                            }
+                            let _ = pending.response_tx.send(reply);
+                        });
+                        continue;
+                    }
+                    _ => (pending.reply)(info),
+                }
+            }
+            Err(err) => DaemonReply::Err(err),
+        };
+        let _ = pending.response_tx.send(reply);
+    }
+}
+
+/// Feed `inputs` into a fork, then tell `done` why any of them could not be
+/// made, once every one has been.
+fn replay_inputs(
+    tab: &Tab,
+    inputs: Vec<RecordedInput>,
+    done: impl FnOnce(Vec<String>) + 'static,
+) {
+    struct Replay {
+        left: usize,
+        failed: Vec<String>,
+        done: Option<Box<dyn FnOnce(Vec<String>)>>,
+    }
+    let replay = Rc::new(RefCell::new(Replay {
+        left: inputs.len(),
+        failed: Vec::new(),
+        done: Some(Box::new(done)),
+    }));
+    let tab_name = tab.id.to_string();
+    for input in inputs {
+        let replay = replay.clone();
+        let tab_name = tab_name.clone();
+        tab.input(
+            input.path,
+            input.value,
+            Box::new(move |result| {
+                let mut replay = replay.borrow_mut();
+                if let Err(err) = result {
+                    replay.failed.push(err.into_daemon_error(tab_name).user_message());
+                }
+                replay.left -= 1;
+                if replay.left > 0 {
+                    return;
+                }
+                let failed = std::mem::take(&mut replay.failed);
+                let done = replay.done.take();
+                drop(replay);
+                if let Some(done) = done {
+                    done(failed);
+                }
+            }),
+        );
+    }
+}
+
+impl MutationProbe {
+    fn new() -> Self {
+        MutationProbe {
+            seen: String::new(),
+            state: Rc::new(RefCell::new(MutationProbeState::Idle)),
+            next_probe: Instant::now(),
+        }
+    }
+
+    /// Advance the probe by one step. Returns `Some` each time a check
+    /// finishes, holding the hypermap if it changed since the last check.
+    fn poll(&mut self, backend: &dyn TabBackend) -> Option<Option<Value>> {
+        let state = std::mem::replace(&mut *self.state.borrow_mut(), MutationProbeState::Idle);
+        match state {
+            MutationProbeState::Idle => {
+                if Instant::now() >= self.next_probe {
+                    *self.state.borrow_mut() = MutationProbeState::Running;
+                    let state_handle = self.state.clone();
+                    backend.probe_mutations(
+                        &self.seen,
+                        Box::new(move |mutations| {
+                            *state_handle.borrow_mut() = MutationProbeState::Done(mutations);
+                        }),
+                    );
+                }
+                None
+            }
+            MutationProbeState::Running => {
+                *self.state.borrow_mut() = MutationProbeState::Running;
+                None
+            }
+            MutationProbeState::Done(mutations) => {
+                self.next_probe = Instant::now() + MUTATION_POLL_INTERVAL;
+                match mutations {
+                    Some(Mutations {
+                        version,
+                        data: Some(data),
+                    }) => {
+                        self.seen = version;
+                        Some(Some(data))
+                    }
+                    _ => Some(None),
+                }
+            }
+        }
+    }
+}
+
+impl PendingWait {
+    /// Advance the wait by one step, returning the outcome once it is settled.
+    fn poll(&mut self, tabs: &[Tab]) -> Option<Result<Value, DaemonError>> {
+        let Some(tab) = tabs.iter().find(|t| t.id == self.tab_id) else {
+            return Some(Err(DaemonError::TabNotFound {
+                tab: self.tab.clone(),
+            }));
+        };
+
+        let Some(hypermap) = self.probe.poll(&*tab.backend) else {
+            // Always let the first check finish, so a zero timeout still
+            // tests the condition once
+            return if self.checked {
+                self.timed_out()
+            } else {
+                None
+            };
+        };
+        if let Some(data) = hypermap {
+            let current = match get_value_at_path(data, &self.path, &self.tab) {
+                Ok(current) => current,
+                Err(err) => return Some(Err(err)),
+            };
+            if self.check.observe(current.as_ref()) {
+                return Some(Ok(current.unwrap_or(Value::Null)));
+            }
+        }
+        self.checked = true;
+        self.timed_out()
+    }
+
+    fn timed_out<T>(&self) -> Option<Result<T, DaemonError>> {
+        (Instant::now() >= self.deadline).then(|| {
+            Err(DaemonError::Timeout {
+                tab: self.tab.clone(),
+                timeout_ms: self.timeout_ms,
+            })
+        })
+    }
+}
+
+/// Reply to every pending Wait whose condition holds or whose time is up.
+fn poll_pending_waits(state: &Rc<RefCell<DaemonState>>) {
+    let mut state_ref = state.borrow_mut();
+    let state_ref = &mut *state_ref;
+    let mut i = 0;
+    while i < state_ref.pending_waits.len() {
+        let Some(outcome) = state_ref.pending_waits[i].poll(&state_ref.tabs) else {
+            i += 1;
+            continue;
+        };
+        let pending = state_ref.pending_waits.swap_remove(i);
+        let reply = match outcome {
+            Ok(value) => DaemonReply::ok_hypermap(value),
+            Err(err) => DaemonReply::Err(err),
+        };
+        let _ = pending.response_tx.send(reply);
+    }
+}
+
+impl ActiveWatch {
+    /// Advance the watch by one step, returning the next reply to stream.
+    /// A reply without `continues` ends the watch.
+    fn poll(&mut self, tabs: &[Tab]) -> Option<DaemonReply> {
+        let Some(tab) = tabs.iter().find(|t| t.id == self.tab_id) else {
+            return Some(DaemonReply::Err(DaemonError::TabNotFound {
+                tab: self.tab.clone(),
+            }));
+        };
+
+        let data = self.probe.poll(&*tab.backend)??;
+        let value = match &self.path {
+            Some(path) => match get_value_at_path(data, path, &self.tab) {
+                Ok(Some(v)) => v,
+                Err(err) => return Some(DaemonReply::Err(err)),
+                Ok(None) => {
+                    return Some(DaemonReply::Err(DaemonError::PathNotFound {
+                        tab: self.tab.clone(),
+                        path: path.clone(),
+                    }));
+                }
+            },
+            None => data,
+        };
+        if self.last.as_ref() == Some(&value) {
+            return None;
+        }
+        self.last = Some(value.clone());
+        Some(DaemonReply::ok_hypermap(value).continuing())
+    }
+}
+
+/// Stream a snapshot to every Watch whose path has changed, dropping the
+/// watches that have ended or whose client has gone away.
+fn poll_watches(state: &Rc<RefCell<DaemonState>>) {
+    let mut state_ref = state.borrow_mut();
+    let state_ref = &mut *state_ref;
+    let tabs = &state_ref.tabs;
+    state_ref.watches.retain_mut(|watch| {
+        // A send only fails once there is something to send, so check the
+        // client on every poll in case the page never changes again
+        if watch.client.as_ref().is_some_and(hung_up) {
+            return false;
+        }
+        match watch.poll(tabs) {
+            Some(reply) => {
+                let continues = reply.continues();
+                watch.response_tx.send(reply).is_ok() && continues
+            }
+            None => true,
+        }
+    });
+}
+
+/// Whether the other end of `stream` has closed, checked without blocking.
+/// Clients shut down their write side once the call is sent, so reading
+/// end-of-file does not mean they have gone.
+fn hung_up(stream: &UnixStream) -> bool {
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: 0,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut fd, 1, 0) };
+    ready > 0 && fd.revents & (libc::POLLHUP | libc::POLLERR) != 0
+}
+
+fn resolve_tab(tabs: &[Tab], tab_ref: &str) -> Option<usize> {
+    // Try parsing as ID first
+    if let Ok(id) = tab_ref.parse::<usize>()
+        && let Some(idx) = tabs.iter().position(|t| t.id == id)
+    {
+        return Some(idx);
+    }
+    // Try finding by name
+    tabs.iter().position(|t| t.name.as_deref() == Some(tab_ref))
+}
+
+/// Look up `path` in a hypermap snapshot, returning the subtree in the same
+/// lossless form. Waits and watches only call this when the page has changed,
+/// so the snapshot is parsed once per change rather than once per poll.
+fn get_value_at_path(value: Value, path: &str, tab: &str) -> Result<Option<Value>, DaemonError> {
+    let node = Node::from_value(value).map_err(|err| DaemonError::InvalidHypermap {
+        tab: tab.to_string(),
+        reason: err.to_string(),
+    })?;
+    Ok(node.at_path(path).map(Node::to_json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fill_control_escapes_data_keys() {
+        let mut root = Node::from_value(json!({
+            "form": {"#": {"href": "/"}, "a/b": "", "c~d": "", "a": {"b": ""}}
+        }))
+        .unwrap();
+        let data = HashMap::from([
+            ("a/b".to_string(), json!("x")),
+            ("c~d".to_string(), json!("y")),
+        ]);
+        assert!(fill_control(&mut root, "form", &data).is_ok());
+        assert_eq!(
+            root.to_json(),
+            json!({"form": {"#": {"href": "/"}, "a/b": "x", "c~d": "y", "a": {"b": ""}}})
+        );
+
+        let data = HashMap::from([("c~0d".to_string(), json!("z"))]);
+        assert!(matches!(
+            fill_control(&mut root, "form", &data),
+            Err(ControlError::PathNotFound(path)) if path == "form/c~00d"
+        ));
+    }
+    #[test]
+    fn invalid_attributes_are_not_a_missing_path() {
+        let data = json!({"form": {"#": {"href": 1}, "q": ""}, "title": "Hi"});
+        assert!(matches!(
+            get_value_at_path(data.clone(), "title", "1"),
+            Err(DaemonError::InvalidHypermap { tab, reason })
+                if tab == "1" && reason.contains("'href' must be a string")
+        ));
+
+        let data = json!({"form": {"#": {"href": "/"}, "q": ""}});
+        assert_eq!(get_value_at_path(data.clone(), "form/q", "1").unwrap(), Some(json!("")));
+        assert_eq!(get_value_at_path(data, "form/r", "1").unwrap(), None);
+    }
+    #[test]
+    fn hung_up_ignores_a_half_closed_client() {
+        let (daemon, client) = UnixStream::pair().unwrap();
+        assert!(!hung_up(&daemon));
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(!hung_up(&daemon));
+        drop(client);
+        assert!(hung_up(&daemon));
+    }
+}
@@ -0,0 +1,273 @@
+// Native backend: HyperMap over plain HTTP
+//
+// Fetches resources as `application/vnd.hypermap+json` and keeps the parsed
+// tree in memory, so a tab costs one short-lived thread per request instead
+// of a browser engine. Scripts declared by the resource are not run: the
+// hypermap only changes through inputs, and control activation is carried
+// out here as the spec describes.
+
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use url::Url;
+
+use mech_cli::fetch::{FetchError, Request, Response};
+use mech_cli::{Backend, Hypermap, LoadState, HYPERMAP_MEDIA_TYPE};
+
+use crate::{
+    fill_control, input_field, Activated, BackgroundFetch, ControlError, History, Inputted,
+    Mutations, Sent, Snapshot, TabBackend, Traversal,
+};
+
+/// A tab that fetches and activates controls itself.
+pub struct NativeTab {
+    page: RefCell<Page>,
+    history: RefCell<History>,
+    /// Number of loads that have replaced the page, whether or not they
+    /// succeeded.
+    completed_loads: Cell<u64>,
+    /// Inputs made since the page loaded, so that Wait and Watch see them
+    /// as mutations.
+    inputs: Cell<u64>,
+    loading: RefCell<Option<Loading>>,
+}
+
+/// The resource a tab is showing.
+struct Page {
+    /// The base URL: where the resource was fetched from after redirects.
+    url: Url,
+    /// The parsed resource, or why it could not be loaded.
+    hypermap: Result<Hypermap, String>,
+}
+
+/// A request in flight.
+struct Loading {
+    fetch: BackgroundFetch,
+    step: HistoryStep,
+    /// Told how the request went, when it was made by activating a control.
+    activated: Option<Activated>,
+}
+
+/// Where a finished load goes in the session history.
+enum HistoryStep {
+    /// After the current entry, replacing any forward entries.
+    Push,
+    /// Over the entry at this index, which becomes the current one.
+    Replace(usize),
+}
+
+impl NativeTab {
+    /// Create a tab and start loading `url`.
+    pub fn new(url: Url) -> Self {
+        let tab = NativeTab {
+            page: RefCell::new(Page {
+                url: url.clone(),
+                hypermap: Err("Page is still loading...".to_string()),
+            }),
+            history: RefCell::new(History::default()),
+            completed_loads: Cell::new(0),
+            inputs: Cell::new(0),
+            loading: RefCell::new(None),
+        };
+        tab.start(Request::get(url), HistoryStep::Push, None);
+        tab
+    }
+
+    /// Send `request` in the background, replacing any load in flight.
+    fn start(&self, request: Request, step: HistoryStep, activated: Option<Activated>) {
+        let superseded = self.loading.borrow_mut().replace(Loading {
+            fetch: BackgroundFetch::start(request, HYPERMAP_MEDIA_TYPE),
+            step,
+            activated,
+        });
+        if let Some(activated) = superseded.and_then(|loading| loading.activated) {
+            activated(Err(ControlError::Superseded));
+        }
+    }
+
+    /// Show the outcome of a finished load and record it in the history.
+    fn finish(&self, loading: Loading, result: Result<Response, FetchError>) {
+        let Loading {
+            fetch,
+            step,
+            activated,
+        } = loading;
+        let page = match result {
+            // Handled without leaving the page, like a browser does
+            Ok(response) if response.status == 204 => {
+                if let Some(activated) = activated {
+                    activated(Ok(false));
+                }
+                return;
+            }
+            Ok(response) => Page {
+                hypermap: parse_response(&response),
+                url: response.url,
+            },
+            // A control whose request fails leaves the page as it was
+            Err(err) if activated.is_some() => {
+                if let Some(activated) = activated {
+                    activated(Err(ControlError::Request(err)));
+                }
+                return;
+            }
+            Err(err) => Page {
+                url: fetch.url,
+                hypermap: Err(err.to_string()),
+            },
+        };
+
+        let mut history = self.history.borrow_mut();
+        match step {
+            HistoryStep::Push => {
+                let keep = (history.current + 1).min(history.entries.len());
+                history.entries.truncate(keep);
+                history.entries.push(page.url.clone());
+                history.current = history.entries.len() - 1;
+            }
+            HistoryStep::Replace(index) => {
+                if let Some(entry) = history.entries.get_mut(index) {
+                    *entry = page.url.clone();
+                    history.current = index;
+                }
+            }
+        }
+        drop(history);
+        self.inputs.set(0);
+        *self.page.borrow_mut() = page;
+        self.completed_loads.set(self.completed_loads.get() + 1);
+        if let Some(activated) = activated {
+            activated(Ok(true));
+        }
+    }
+}
+
+/// Parse a response body, saying how it was served if it is not HyperMap.
+fn parse_response(response: &Response) -> Result<Hypermap, String> {
+    response.hypermap().map_err(|e| {
+        if response.is_hypermap() {
+            format!("{} is not a valid HyperMap resource: {}", response.url, e)
+        } else {
+            format!(
+                "{} was served as '{}', not {}",
+                response.url, response.content_type, HYPERMAP_MEDIA_TYPE
+            )
+        }
+    })
+}
+
+impl TabBackend for NativeTab {
+    fn kind(&self) -> Backend {
+        Backend::Native
+    }
+
+    fn url(&self) -> String {
+        self.page.borrow().url.to_string()
+    }
+
+    fn load_state(&self) -> LoadState {
+        if self.loading.borrow().is_some() {
+            LoadState::Loading
+        } else {
+            LoadState::Complete
+        }
+    }
+
+    fn completed_loads(&self) -> u64 {
+        self.completed_loads.get()
+    }
+
+    fn history(&self) -> History {
+        self.history.borrow().clone()
+    }
+
+    fn load(&self, url: Url) {
+        self.start(Request::get(url), HistoryStep::Push, None);
+    }
+
+    fn traverse(&self, traversal: Traversal) {
+        let target = {
+            let history = self.history.borrow();
+            let index = match traversal {
+                Traversal::Back => history.current.checked_sub(1),
+                Traversal::Forward => Some(history.current + 1),
+                Traversal::Reload => Some(history.current),
+            };
+            index.and_then(|i| Some((i, history.entries.get(i)?.clone())))
+        };
+        if let Some((index, url)) = target {
+            self.start(Request::get(url), HistoryStep::Replace(index), None);
+        }
+    }
+
+    fn query(&self, done: Box<dyn FnOnce(Result<Snapshot, String>)>) {
+        let page = self.page.borrow();
+        done(match &page.hypermap {
+            Ok(hypermap) => Ok(Snapshot {
+                url: page.url.to_string(),
+                data: hypermap.as_node().to_json(),
+            }),
+            Err(message) => Err(message.clone()),
+        });
+    }
+
+    fn input(&self, path: &str, value: &Value, done: Inputted) {
+        let result = match &mut self.page.borrow_mut().hypermap {
+            Ok(hypermap) => input_field(hypermap.as_node_mut(), path, value),
+            // A page without a hypermap has no fields
+            Err(_) => Err(ControlError::PathNotFound(path.to_string())),
+        };
+        if result.is_ok() {
+            self.inputs.set(self.inputs.get() + 1);
+        }
+        done(result);
+    }
+
+    fn use_control(&self, path: &str, data: &HashMap<String, Value>, sent: Sent, done: Activated) {
+        let request = {
+            let mut page = self.page.borrow_mut();
+            let Page { url, hypermap } = &mut *page;
+            let control = match hypermap {
+                Ok(hypermap) => fill_control(hypermap.as_node_mut(), path, data),
+                Err(_) => Err(ControlError::PathNotFound(path.to_string())),
+            };
+            control.map(|control| Request::for_control(control, url))
+        };
+        if request.is_ok() && !data.is_empty() {
+            self.inputs.set(self.inputs.get() + 1);
+        }
+        match request {
+            Ok(Ok(request)) => {
+                sent(Ok(Some(request.url.clone())));
+                self.start(request, HistoryStep::Push, Some(done));
+            }
+            Ok(Err(err)) => sent(Err(ControlError::Request(err))),
+            Err(err) => sent(Err(err)),
+        }
+    }
+
+    fn probe_mutations(&self, seen: &str, done: Box<dyn FnOnce(Option<Mutations>)>) {
+        let version = format!("{}:{}", self.completed_loads.get(), self.inputs.get());
+        let page = self.page.borrow();
+        let data = match &page.hypermap {
+            Ok(hypermap) if version != seen => Some(hypermap.as_node().to_json()),
+            _ => None,
+        };
+        done(Some(Mutations { version, data }));
+    }
+
+    fn spin(&self) {
+        let result = match &*self.loading.borrow() {
+            Some(loading) => loading.fetch.poll(),
+            None => None,
+        };
+        let Some(result) = result else {
+            return;
+        };
+        let loading = self.loading.borrow_mut().take();
+        if let Some(loading) = loading {
+            self.finish(loading, result);
+        }
+    }
+}
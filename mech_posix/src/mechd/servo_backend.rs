@@ -0,0 +1,764 @@
+// Servo backend: a headless browser per tab
+//
+// Each tab is a Servo WebView that loads the page, runs the HyperMap shim and
+// the resource's scripts, and is driven by injecting scripts into the page.
+// Controls are activated here rather than by the shim, which only follows
+// redirects: the request is made in Rust and its response handed to the
+// WebView in place of the navigation that shows it.
+
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use dpi::PhysicalSize;
+use servo::{
+    JSValue, LoadStatus, RenderingContext, Servo, ServoBuilder, ServoDelegate,
+    SoftwareRenderingContext, WebResourceLoad, WebResourceResponse, WebView, WebViewBuilder,
+    WebViewDelegate,
+};
+use ureq::http::header::CONTENT_TYPE;
+use ureq::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use url::Url;
+
+use mech_cli::fetch::{FetchError, Request, Response};
+use mech_cli::{Backend, DaemonError, LoadState, Node};
+
+use crate::{
+    Activated, BackgroundFetch, ControlError, History, Inputted, Mutations, Sent, Snapshot,
+    TabBackend, Traversal,
+};
+
+/// What a control's request asks for: the pages Servo shows are HTML that
+/// loads the shim.
+const HTML_ACCEPT: &str = "text/html,application/xhtml+xml,*/*;q=0.8";
+
+/// Start the Servo engine shared by every Servo tab.
+pub fn start() -> Servo {
+    let servo = ServoBuilder::default().build();
+    servo.set_delegate(Rc::new(MechServoDelegate));
+    servo
+}
+
+/// Convert Servo's JSValue to serde_json::Value
+fn jsvalue_to_json(jsval: &JSValue) -> Value {
+    match jsval {
+        JSValue::Undefined | JSValue::Null => Value::Null,
+        JSValue::Boolean(b) => Value::Bool(*b),
+        JSValue::Number(n) => js_number(*n),
+        JSValue::String(s) => Value::String(s.clone()),
+        JSValue::Array(arr) => Value::Array(arr.iter().map(jsvalue_to_json).collect()),
+        JSValue::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), jsvalue_to_json(v)))
+                .collect(),
+        ),
+        // DOM references - just return their string representation
+        JSValue::Element(s) | JSValue::ShadowRoot(s) | JSValue::Frame(s) | JSValue::Window(s) => {
+            Value::String(s.clone())
+        }
+    }
+}
+
+/// A JavaScript number as JSON: whole numbers as integers, so a quantity of 5
+/// reads `5` rather than `5.0`. NaN, the infinities and -0, which JSON text
+/// cannot carry or `JSON.stringify()` turns into `null` or `0`, become a
+/// `{"$number": "NaN"}` object naming them, so they cannot be mistaken for
+/// strings.
+fn js_number(n: f64) -> Value {
+    let tagged = |name: &str| json!({ "$number": name });
+    if n == 0.0 && n.is_sign_negative() {
+        return tagged("-0");
+    }
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+        return Value::from(n as i64);
+    }
+    match serde_json::Number::from_f64(n) {
+        Some(num) => Value::Number(num),
+        None if n.is_nan() => tagged("NaN"),
+        None if n > 0.0 => tagged("Infinity"),
+        None => tagged("-Infinity"),
+    }
+}
+
+/// Serialize a shim node tree losslessly, `#` attributes included, as the
+/// spec's `toJSON()` does. The shim's own `toJSON()` only marks controls.
+/// Numbers that are not finite, and -0, are tagged as `js_number` tags them.
+const LOSSLESS_JSON_SCRIPT: &str = r#"
+    function losslessJSON(node) {
+        if (node.innerMap instanceof Map) {
+            const out = {};
+            const attributes = JSON.parse(JSON.stringify(node.attributes || {}));
+            if (Object.keys(attributes).length > 0) {
+                out['#'] = attributes;
+            }
+            node.innerMap.forEach((child, key) => { out[key] = losslessJSON(child); });
+            return out;
+        }
+        if (Array.isArray(node.innerArray)) {
+            return node.innerArray.map(losslessJSON);
+        }
+        if (typeof node.value === 'number' && Object.is(node.value, -0)) {
+            return { '$number': '-0' };
+        }
+        if (typeof node.value === 'number' && !Number.isFinite(node.value)) {
+            return { '$number': String(node.value) };
+        }
+        return node.value === undefined ? null : node.value;
+    }
+"#;
+
+/// Look up a node by a slash-separated path the way `Node::at_path` does,
+/// returning `undefined` where it would return `None`. `pathKeys` splits and
+/// unescapes a path as `path_keys` does. The shim's own `nodeFromPath()`
+/// throws on a missing key, and reads a list at any key that is not a
+/// number as if it were index 0. `fieldPath` joins a control's path and a
+/// key of its data, escaping the key, as `join_path` does. `isValue` tells the leaves
+/// `Hypermap.input()` can change from maps and lists.
+const NODE_AT_SCRIPT: &str = r#"
+    function pathKeys(path) {
+        const segments = path.startsWith('/')
+            ? path.slice(1).split('/')
+            : path.split('/').filter((segment) => segment !== '');
+        if (segments.some((segment) => /~(?![01])/.test(segment))) {
+            return undefined;
+        }
+        return segments.map((segment) => segment.replace(/~1/g, '/').replace(/~0/g, '~'));
+    }
+    function nodeAt(path) {
+        const keys = pathKeys(path);
+        if (!keys) {
+            return undefined;
+        }
+        let node = window.hypermap;
+        for (const key of keys) {
+            if (node && node.innerMap instanceof Map) {
+                node = node.innerMap.get(key);
+            } else if (node && Array.isArray(node.innerArray) && /^-?\d+$/.test(key)) {
+                node = node.innerArray.at(Number(key));
+            } else {
+                return undefined;
+            }
+        }
+        return node;
+    }
+    function fieldPath(path, key) {
+        key = key.replace(/~/g, '~0').replace(/\//g, '~1');
+        if (path.startsWith('/')) {
+            return path + '/' + key;
+        }
+        if (key === '') {
+            return path.split('/').filter((segment) => segment !== '').concat([''])
+                .map((segment) => '/' + segment).join('');
+        }
+        return path === '' ? key : path + '/' + key;
+    }
+    function isValue(node) {
+        return !(node.innerMap instanceof Map) && !Array.isArray(node.innerArray);
+    }
+"#;
+
+/// A script that calls `function` with `args`, each written as a JSON
+/// literal. Paths and values reach the page exactly as given, whatever
+/// characters they contain, where Rust's `Debug` escapes are not always
+/// valid JavaScript. The line and paragraph separators JSON leaves as they
+/// are in strings are escaped too, for engines that predate ES2019.
+fn call_script(function: &str, args: &[Value]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            let arg = arg.to_string();
+            arg.replace('\u{2028}', "\\u2028").replace('\u{2029}', "\\u2029")
+        })
+        .collect();
+    format!("({})({})", function, args.join(", "))
+}
+
+/// Parse the hypermap an injected script returned under `key`. Scripts
+/// return it as a JSON string because Servo hands objects back as hash maps,
+/// which would lose the order of their keys.
+fn json_member(response: &Value, key: &str) -> Option<Value> {
+    serde_json::from_str(response.get(key)?.as_str()?).ok()
+}
+
+/// Query the current hypermap state.
+/// Returns the hypermap and URL if available, or diagnostic info if not.
+fn hypermap_query_script() -> String {
+    format!(
+        r#"
+        (function() {{
+            {}
+            if (window.hypermap) {{
+                return {{
+                    ok: true,
+                    url: window.location.href,
+                    data: JSON.stringify(losslessJSON(window.hypermap))
+                }};
+            }}
+            return {{
+                ok: false,
+                readyState: document.readyState,
+                title: document.title || null,
+                bodyText: document.body ? document.body.innerText.slice(0, 200) : null,
+                hasPre: !!document.querySelector('pre')
+            }};
+        }})()
+        "#,
+        LOSSLESS_JSON_SCRIPT
+    )
+}
+
+/// Count the shim's `mutation` events, and the `input` events that
+/// `Hypermap.input()` fires on the hypermap instead when it changes a value,
+/// and return the hypermap only when it has mutated (or the page has
+/// changed) since the version `seen`.
+fn mutation_probe_script(seen: &str) -> String {
+    let function = format!(
+        r#"
+        function(seen) {{
+            {}
+            let m = window.__mechMutations;
+            if (!m) {{
+                m = window.__mechMutations = {{
+                    page: Math.random().toString(36).slice(2),
+                    count: 0,
+                    hypermap: null
+                }};
+                window.addEventListener('mutation', () => {{ m.count++; }});
+            }}
+            if (window.hypermap && m.hypermap !== window.hypermap) {{
+                m.hypermap = window.hypermap;
+                m.hypermap.addEventListener('input', () => {{ m.count++; }});
+            }}
+            const version = m.page + ':' + m.count;
+            if (version === seen || !window.hypermap) {{
+                return {{ version: version }};
+            }}
+            return {{
+                version: version,
+                data: JSON.stringify(losslessJSON(window.hypermap))
+            }};
+        }}
+        "#,
+        LOSSLESS_JSON_SCRIPT
+    );
+    call_script(&function, &[seen.into()])
+}
+
+/// Script that feeds `value` into the field at `path`, as `Hypermap.input()`
+/// would from the page. Returns `{ ok: true }`, or the error as
+/// `script_error` reads it.
+fn input_script(path: &str, value: &Value) -> String {
+    let function = format!(
+        r#"
+        function(path, value) {{
+            {}
+            try {{
+                const node = window.hypermap && nodeAt(path);
+                if (!node) {{
+                    return {{ error: 'PathNotFound', path: path }};
+                }}
+                if (!isValue(node)) {{
+                    return {{ error: 'NotAValue', path: path }};
+                }}
+                window.hypermap.input(pathKeys(path), value);
+                return {{ ok: true }};
+            }} catch (e) {{
+                return {{ error: 'ScriptError', message: String(e) }};
+            }}
+        }}
+        "#,
+        NODE_AT_SCRIPT
+    );
+    call_script(&function, &[path.into(), value.clone()])
+}
+
+/// Fill in `data` below the control at `path`, then dispatch the use event
+/// the same way `Hypermap.use()` does. Nothing is filled in unless `path` is
+/// a control and every field is a value. Scripts on the control and its
+/// ancestors get the first chance to handle the event; if none of them
+/// prevents the default action, it is prevented once the event reaches the
+/// root so that the shim leaves it to mechd. Returns the control and the
+/// page's URL if mechd should activate it, or the error as `script_error`
+/// reads it.
+fn use_script(path: &str, data: &HashMap<String, Value>) -> String {
+    let function = format!(
+        r#"
+        function(path, data) {{
+            {}
+            {}
+            try {{
+                const node = window.hypermap && nodeAt(path);
+                if (!node) {{
+                    return {{ error: 'PathNotFound', path: path }};
+                }}
+                if (!(node.attributes && node.attributes.href)) {{
+                    return {{ error: 'NotAControl', path: path }};
+                }}
+                const fields = Object.keys(data).map((key) => fieldPath(path, key));
+                for (const field of fields) {{
+                    const target = nodeAt(field);
+                    if (!target) {{
+                        return {{ error: 'PathNotFound', path: field }};
+                    }}
+                    if (!isValue(target)) {{
+                        return {{ error: 'NotAValue', path: field }};
+                    }}
+                }}
+                Object.values(data).forEach((value, i) => {{
+                    window.hypermap.input(pathKeys(fields[i]), value);
+                }});
+
+                const event = new CustomEvent('use', {{
+                    bubbles: true,
+                    cancelable: true,
+                    detail: {{ target: node }},
+                }});
+                let activate = false;
+                const claim = (e) => {{
+                    if (e === event && !e.defaultPrevented) {{
+                        activate = true;
+                        e.preventDefault();
+                    }}
+                }};
+                window.hypermap.addEventListener('use', claim);
+                try {{
+                    node.dispatchEvent(event);
+                }} finally {{
+                    window.hypermap.removeEventListener('use', claim);
+                }}
+                if (!activate) {{
+                    return {{ activate: false }};
+                }}
+                return {{
+                    activate: true,
+                    base: window.location.href,
+                    control: JSON.stringify(losslessJSON(node))
+                }};
+            }} catch (e) {{
+                return {{ error: 'ScriptError', message: String(e) }};
+            }}
+        }}
+        "#,
+        LOSSLESS_JSON_SCRIPT, NODE_AT_SCRIPT
+    );
+    let data = data.iter().map(|(key, value)| (key.clone(), value.clone()));
+    call_script(&function, &[path.into(), Value::Object(data.collect())])
+}
+
+/// The error an injected script returned, if it returned one.
+fn script_error(response: &Value) -> Option<ControlError> {
+    let member = |key| {
+        let value = response.get(key).and_then(Value::as_str);
+        value.unwrap_or_default().to_string()
+    };
+    Some(match response.get("error")?.as_str()? {
+        "PathNotFound" => ControlError::PathNotFound(member("path")),
+        "NotAValue" => ControlError::NotAValue(member("path")),
+        "NotAControl" => ControlError::NotAControl(member("path")),
+        _ => ControlError::Script(member("message")),
+    })
+}
+
+/// The request for the control `use_script` returned.
+fn control_request(response: &Value) -> Result<Request, FetchError> {
+    let base = response.get("base").and_then(Value::as_str);
+    let base = base.and_then(|b| Url::parse(b).ok());
+    let control = json_member(response, "control");
+    let control = control.and_then(|c| Node::from_value(c).ok());
+    match (control.as_ref().and_then(Node::as_map), base) {
+        (Some(control), Some(base)) => Request::for_control(control, &base),
+        _ => Err(FetchError::NotAControl),
+    }
+}
+
+/// A tab backed by its own Servo WebView.
+pub struct ServoTab {
+    webview: WebView,
+    /// The URL the tab was opened with, reported until Servo commits a load.
+    url: Url,
+    /// Number of loads that have reached `LoadStatus::Complete`, shared with
+    /// the tab's `MechWebViewDelegate`.
+    completed_loads: Rc<Cell<u64>>,
+    /// Session history, kept up to date by the tab's `MechWebViewDelegate`.
+    history: Rc<RefCell<History>>,
+    /// The request made by activating a control, while it is in flight.
+    activation: Rc<RefCell<Option<Activation>>>,
+    /// The response to that request, which the tab's `MechWebViewDelegate`
+    /// serves to the navigation that shows it.
+    activated: Rc<RefCell<Option<Response>>>,
+}
+
+struct Activation {
+    fetch: BackgroundFetch,
+    done: Activated,
+}
+
+/// Put `next` in place of the activation in flight, if any, and tell the
+/// one it replaces that another load superseded it.
+fn supersede(activation: &RefCell<Option<Activation>>, next: Option<Activation>) {
+    let superseded = std::mem::replace(&mut *activation.borrow_mut(), next);
+    if let Some(superseded) = superseded {
+        (superseded.done)(Err(ControlError::Superseded));
+    }
+}
+
+impl ServoTab {
+    /// Create a WebView loading `url`.
+    pub fn new(servo: &Servo, url: Url) -> Result<Self, DaemonError> {
+        let size = PhysicalSize::new(1024, 768);
+        let rendering_context: Rc<dyn RenderingContext> =
+            match SoftwareRenderingContext::new(size) {
+                Ok(ctx) => Rc::new(ctx),
+                Err(e) => {
+                    return Err(DaemonError::PageError {
+                        message: format!("Failed to create rendering context: {:?}", e),
+                    });
+                }
+            };
+
+        let completed_loads = Rc::new(Cell::new(0));
+        let history = Rc::new(RefCell::new(History::default()));
+        let activated = Rc::new(RefCell::new(None));
+        let delegate = Rc::new(MechWebViewDelegate {
+            completed_loads: completed_loads.clone(),
+            history: history.clone(),
+            activated: activated.clone(),
+        });
+
+        let webview = WebViewBuilder::new(servo, rendering_context)
+            .url(url.clone())
+            .delegate(delegate)
+            .build();
+
+        Ok(ServoTab {
+            webview,
+            url,
+            completed_loads,
+            history,
+            activation: Rc::new(RefCell::new(None)),
+            activated,
+        })
+    }
+}
+
+impl TabBackend for ServoTab {
+    fn kind(&self) -> Backend {
+        Backend::Servo
+    }
+
+    fn url(&self) -> String {
+        self.webview
+            .url()
+            .map(|u| u.to_string())
+            .unwrap_or_else(|| self.url.to_string())
+    }
+
+    fn load_state(&self) -> LoadState {
+        match self.webview.load_status() {
+            LoadStatus::Started | LoadStatus::HeadParsed => LoadState::Loading,
+            LoadStatus::Complete => LoadState::Complete,
+        }
+    }
+
+    fn completed_loads(&self) -> u64 {
+        self.completed_loads.get()
+    }
+
+    fn history(&self) -> History {
+        self.history.borrow().clone()
+    }
+
+    fn load(&self, url: Url) {
+        supersede(&self.activation, None);
+        self.webview.load(url);
+    }
+
+    fn traverse(&self, traversal: Traversal) {
+        supersede(&self.activation, None);
+        match traversal {
+            Traversal::Back => {
+                self.webview.go_back(1);
+            }
+            Traversal::Forward => {
+                self.webview.go_forward(1);
+            }
+            Traversal::Reload => self.webview.reload(),
+        }
+    }
+
+    fn query(&self, done: Box<dyn FnOnce(Result<Snapshot, String>)>) {
+        self.webview
+            .evaluate_javascript(hypermap_query_script(), move |result| match result {
+                Ok(jsval) => {
+                    let response = jsvalue_to_json(&jsval);
+                    if response.get("ok") == Some(&Value::Bool(true)) {
+                        let url = response.get("url").and_then(|u| u.as_str());
+                        let url = url.unwrap_or_default().to_string();
+                        done(Ok(Snapshot {
+                            url,
+                            data: json_member(&response, "data").unwrap_or(Value::Null),
+                        }));
+                    } else {
+                        done(Err(format_load_error(&response)));
+                    }
+                }
+                Err(e) => done(Err(format!("Failed to query page: {:?}", e))),
+            });
+    }
+
+    fn input(&self, path: &str, value: &Value, done: Inputted) {
+        self.webview
+            .evaluate_javascript(input_script(path, value), move |result| {
+                let response = match result {
+                    Ok(jsval) => jsvalue_to_json(&jsval),
+                    Err(e) => return done(Err(ControlError::Script(format!("{:?}", e)))),
+                };
+                match script_error(&response) {
+                    Some(err) => done(Err(err)),
+                    None => done(Ok(())),
+                }
+            });
+    }
+
+    fn use_control(&self, path: &str, data: &HashMap<String, Value>, sent: Sent, done: Activated) {
+        let activation = self.activation.clone();
+        self.webview
+            .evaluate_javascript(use_script(path, data), move |result| {
+                let response = match result {
+                    Ok(jsval) => jsvalue_to_json(&jsval),
+                    Err(e) => return sent(Err(ControlError::Script(format!("{:?}", e)))),
+                };
+                if let Some(err) = script_error(&response) {
+                    sent(Err(err));
+                    return;
+                }
+                if response.get("activate") != Some(&Value::Bool(true)) {
+                    sent(Ok(None));
+                    done(Ok(false));
+                    return;
+                }
+                match control_request(&response) {
+                    Ok(request) => {
+                        sent(Ok(Some(request.url.clone())));
+                        let next = Activation {
+                            fetch: BackgroundFetch::start(request, HTML_ACCEPT),
+                            done,
+                        };
+                        supersede(&activation, Some(next));
+                    }
+                    Err(err) => sent(Err(ControlError::Request(err))),
+                }
+            });
+    }
+
+    fn probe_mutations(&self, seen: &str, done: Box<dyn FnOnce(Option<Mutations>)>) {
+        self.webview
+            .evaluate_javascript(mutation_probe_script(seen), move |result| {
+                let response = result.map(|v| jsvalue_to_json(&v)).unwrap_or_default();
+                let version = response.get("version").and_then(|v| v.as_str());
+                let version = version.map(str::to_string);
+                done(version.map(|version| Mutations {
+                    version,
+                    data: json_member(&response, "data"),
+                }));
+            });
+    }
+
+    fn spin(&self) {
+        let result = match &*self.activation.borrow() {
+            Some(activation) => activation.fetch.poll(),
+            None => None,
+        };
+        let Some(result) = result else {
+            return;
+        };
+        let Some(activation) = self.activation.borrow_mut().take() else {
+            return;
+        };
+        match result {
+            // Handled without leaving the page, like a browser does
+            Ok(response) if response.status == 204 => (activation.done)(Ok(false)),
+            Ok(response) => {
+                let url = response.url.clone();
+                *self.activated.borrow_mut() = Some(response);
+                self.webview.load(url);
+                (activation.done)(Ok(true));
+            }
+            Err(err) => (activation.done)(Err(ControlError::Request(err))),
+        }
+    }
+}
+
+/// Delegate for handling Servo-level events
+struct MechServoDelegate;
+
+impl ServoDelegate for MechServoDelegate {
+    fn notify_error(&self, error: servo::ServoError) {
+        // Log error but don't crash the daemon
+        eprintln!("Servo error: {:?}", error);
+    }
+}
+
+/// Delegate for handling WebView-level events
+struct MechWebViewDelegate {
+    completed_loads: Rc<Cell<u64>>,
+    history: Rc<RefCell<History>>,
+    activated: Rc<RefCell<Option<Response>>>,
+}
+
+impl WebViewDelegate for MechWebViewDelegate {
+    fn notify_load_status_changed(&self, _webview: WebView, status: LoadStatus) {
+        // Count finished loads so that pending Open/Use replies can tell a
+        // fresh page from the one that was showing when they were issued.
+        if status == LoadStatus::Complete {
+            self.completed_loads.set(self.completed_loads.get() + 1);
+        }
+    }
+
+    fn notify_history_changed(&self, _webview: WebView, entries: Vec<Url>, current: usize) {
+        *self.history.borrow_mut() = History { entries, current };
+    }
+
+    fn load_web_resource(&self, _webview: WebView, load: WebResourceLoad) {
+        let request = load.request();
+        if !request.is_for_main_frame {
+            return;
+        }
+        // Only the navigation straight after an activation is answered from
+        // its response; anything else lets it go stale
+        let Some(response) = self.activated.borrow_mut().take() else {
+            return;
+        };
+        if request.method != Method::GET || request.url != response.url {
+            return;
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Ok(content_type) = HeaderValue::from_str(&response.content_type) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+        let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+        let reason = status.canonical_reason().unwrap_or_default();
+        let mut intercepted = load.intercept(
+            WebResourceResponse::new(response.url)
+                .headers(headers)
+                .status_code(status)
+                .status_message(reason.as_bytes().to_vec()),
+        );
+        intercepted.send_body_data(response.body.into_bytes());
+        intercepted.finish();
+    }
+
+    fn notify_new_frame_ready(&self, webview: WebView) {
+        // Paint the frame (required for rendering pipeline to progress)
+        webview.paint();
+    }
+
+    fn notify_crashed(&self, _webview: WebView, reason: String, _backtrace: Option<String>) {
+        // Log crash but don't take down the daemon
+        // The tab will remain but with no content
+        eprintln!("WebView crashed: {}", reason);
+    }
+}
+
+/// Build a diagnostic message describing why hypermap content is unavailable.
+/// The returned string is wrapped in `DaemonError::PageError`, so it carries
+/// no trailing newline.
+fn format_load_error(diagnostics: &Value) -> String {
+    let ready_state = diagnostics
+        .get("readyState")
+        .and_then(|v| v.as_str())
+        .unwrap_or("unknown");
+    let title = diagnostics
+        .get("title")
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    let body_text = diagnostics
+        .get("bodyText")
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    let has_pre = diagnostics
+        .get("hasPre")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let title_lower = title.to_lowercase();
+    let body_lower = body_text.to_lowercase();
+
+    if title_lower.contains("not found") || body_lower.contains("not found") || title.contains("404")
+    {
+        return format!("Page not found (404): {}", title);
+    }
+
+    if title_lower.contains("error") || body_lower.contains("error") {
+        let error_hint = if !title.is_empty() {
+            title
+        } else {
+            body_text.lines().next().unwrap_or("Unknown error")
+        };
+        return format!("Page error: {}", error_hint);
+    }
+
+    if ready_state == "loading" {
+        return "Page is still loading...".to_string();
+    }
+
+    if ready_state == "complete" && !has_pre {
+        return "Page loaded but contains no hypermap content (no <pre> element)".to_string();
+    }
+
+    if ready_state == "complete" && has_pre {
+        return "Page loaded but hypermap failed to initialize (check if page serves valid JSON)"
+            .to_string();
+    }
+
+    format!(
+        "No hypermap content (readyState: {}, title: {})",
+        ready_state,
+        if title.is_empty() { "<none>" } else { title }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn whole_numbers_stay_integers() {
+        assert_eq!(js_number(5.0).to_string(), "5");
+        assert_eq!(js_number(-3.0).to_string(), "-3");
+        assert_eq!(js_number(1e17), json!(100_000_000_000_000_000_i64));
+        assert_eq!(js_number(200.5).to_string(), "200.5");
+        assert_eq!(js_number(1e300), json!(1e300));
+        assert_eq!(js_number(0.0).to_string(), "0");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_tagged() {
+        assert_eq!(js_number(f64::NAN), json!({"$number": "NaN"}));
+        assert_eq!(js_number(f64::INFINITY), json!({"$number": "Infinity"}));
+        assert_eq!(js_number(f64::NEG_INFINITY), json!({"$number": "-Infinity"}));
+        assert_eq!(js_number(-0.0), json!({"$number": "-0"}));
+    }
+
+    #[test]
+    fn script_arguments_are_json() {
+        let script = call_script("f", &[json!("a/\"b\"\\c"), json!(5), json!(null)]);
+        assert_eq!(script, r#"(f)("a/\"b\"\\c", 5, null)"#);
+        let script = call_script("f", &[json!("\u{1f600}\u{7}\n")]);
+        assert_eq!(script, "(f)(\"\u{1f600}\\u0007\\n\")");
+        let script = call_script("f", &[json!("a\u{2028}b\u{2029}")]);
+        assert_eq!(script, r#"(f)("a\u2028b\u2029")"#);
+    }
+
+    #[test]
+    fn script_errors_keep_the_path_or_message() {
+        let err = script_error(&json!({"error": "NotAControl", "path": "market/acrn/price"}));
+        assert!(matches!(err, Some(ControlError::NotAControl(path)) if path == "market/acrn/price"));
+        let err = script_error(&json!({"error": "ScriptError", "message": "TypeError: x"}));
+        assert!(matches!(err, Some(ControlError::Script(message)) if message == "TypeError: x"));
+        assert!(script_error(&json!({"activate": false})).is_none());
+    }
+}
@@ -0,0 +1,402 @@
+// Selectors for `mech show`
+//
+// A selector is a path whose segments can match more than one node: `*`
+// matches every child of a map or list, and `**` any number of levels,
+// including none. Bracketed filters after a segment pick items out of the
+// lists it matched (`items[0]`, `items[-1]`, `items[0:10]`) or keep only the
+// nodes with a field (`todos/*[due]`) or with a field equal to a value
+// (`market/*[ticker=ACRN]`). Keys are escaped as in any other path.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::hypermap::{join_path, path_keys, unescape_key, Node};
+
+/// Whether `path` is meant as a selector rather than the path of a single
+/// node: it has a `*` or a `[`, and is not a JSON Pointer, which always
+/// names exactly one node.
+pub fn is_selector(path: &str) -> bool {
+    !path.starts_with('/') && path.contains(['*', '['])
+}
+
+/// A parsed selector, to be matched against a hypermap with `select`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    steps: Vec<Step>,
+}
+
+/// One segment: the nodes it descends to, then the filters applied to them.
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    name: Name,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Name {
+    /// One child, by key or list index.
+    Key(String),
+    /// `*`: every child.
+    Children,
+    /// `**`: the node itself and every node below it.
+    Descendants,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    /// `[N]`: one item of each list; negative indices count from the end.
+    Index(isize),
+    /// `[start:end]`: a range of items of each list, as Python slices them.
+    Slice(Option<isize>, Option<isize>),
+    /// `[path]`: the nodes that have something at `path`.
+    Has(Vec<String>),
+    /// `[path=text]`: the nodes with a value at `path` that is either the
+    /// string `text` or, if `text` is JSON, the value it encodes.
+    Equals {
+        path: Vec<String>,
+        text: String,
+        json: Option<Value>,
+    },
+}
+
+/// Why a selector could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError {
+    pub reason: String,
+}
+
+impl SelectorError {
+    fn new(reason: impl Into<String>) -> Self {
+        SelectorError {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector, SelectorError> {
+        let steps = split_segments(selector)?.into_iter().map(parse_step);
+        Ok(Selector {
+            steps: steps.collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Every node below `root` the selector matches, with its path, in
+    /// document order. A node matched more than one way is listed once.
+    pub fn select<'a>(&self, root: &'a Node) -> Vec<(String, &'a Node)> {
+        let mut matches = vec![(String::new(), root)];
+        for step in &self.steps {
+            matches = step.apply(matches);
+        }
+        matches
+    }
+}
+
+impl Step {
+    fn apply<'a>(&self, nodes: Vec<(String, &'a Node)>) -> Vec<(String, &'a Node)> {
+        let mut matches = Vec::new();
+        for (path, node) in nodes {
+            match &self.name {
+                Name::Key(key) => matches.extend(child(&path, node, key)),
+                Name::Children => matches.extend(children(&path, node)),
+                Name::Descendants => descendants(path, node, &mut matches),
+            }
+        }
+        for filter in &self.filters {
+            matches = filter.apply(matches);
+        }
+        let mut seen = HashSet::new();
+        matches.retain(|(path, _)| seen.insert(path.clone()));
+        matches
+    }
+}
+
+impl Filter {
+    fn apply<'a>(&self, nodes: Vec<(String, &'a Node)>) -> Vec<(String, &'a Node)> {
+        match self {
+            Filter::Index(index) => nodes
+                .into_iter()
+                .filter(|(_, node)| matches!(node, Node::List(_)))
+                .filter_map(|(path, node)| child(&path, node, &index.to_string()))
+                .collect(),
+            Filter::Slice(start, end) => {
+                let mut matches = Vec::new();
+                for (path, node) in nodes {
+                    let Node::List(list) = node else { continue };
+                    let size = list.size();
+                    let bound = |index: Option<isize>, default| match index {
+                        None => default,
+                        Some(i) if i < 0 => size.saturating_sub(i.unsigned_abs()),
+                        Some(i) => size.min(i as usize),
+                    };
+                    let (start, end) = (bound(*start, 0), bound(*end, size));
+                    let items = list.iter().enumerate().skip(start).take(end.saturating_sub(start));
+                    for (i, item) in items {
+                        matches.push((join_path(&path, &i.to_string()), item));
+                    }
+                }
+                matches
+            }
+            Filter::Has(field) => nodes
+                .into_iter()
+                .filter(|(_, node)| node.node_from_path(field).is_some())
+                .collect(),
+            Filter::Equals { path, text, json } => nodes
+                .into_iter()
+                .filter(|(_, node)| {
+                    let field = node.node_from_path(path).and_then(Node::as_value);
+                    field.is_some_and(|field| {
+                        field.value.as_str() == Some(text) || json.as_ref() == Some(&field.value)
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The child of `node` at `key`, with its path. List items are given by
+/// their index from the start even when `key` counts from the end.
+fn child<'a>(path: &str, node: &'a Node, key: &str) -> Option<(String, &'a Node)> {
+    match node {
+        Node::List(list) => {
+            let index = key.parse().ok()?;
+            let item = list.at(index)?;
+            let index = list.resolve(index)?;
+            Some((join_path(path, &index.to_string()), item))
+        }
+        _ => Some((join_path(path, key), node.child(key)?)),
+    }
+}
+
+fn children<'a>(path: &str, node: &'a Node) -> Vec<(String, &'a Node)> {
+    match node {
+        Node::Map(map) => map
+            .iter()
+            .map(|(key, child)| (join_path(path, key), child))
+            .collect(),
+        Node::List(list) => list
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (join_path(path, &i.to_string()), item))
+            .collect(),
+        Node::Value(_) => Vec::new(),
+    }
+}
+
+fn descendants<'a>(path: String, node: &'a Node, matches: &mut Vec<(String, &'a Node)>) {
+    let below = children(&path, node);
+    matches.push((path, node));
+    for (path, child) in below {
+        descendants(path, child, matches);
+    }
+}
+
+/// Split a selector at the slashes outside brackets, dropping empty
+/// segments as paths do.
+fn split_segments(selector: &str) -> Result<Vec<&str>, SelectorError> {
+    let mut segments = Vec::new();
+    let (mut start, mut i) = (0, 0);
+    while let Some(offset) = selector[i..].find(['/', '[']) {
+        let at = i + offset;
+        if selector[at..].starts_with('[') {
+            i = at + 1 + bracket_len(&selector[at + 1..])? + 1;
+        } else {
+            segments.push(&selector[start..at]);
+            start = at + 1;
+            i = start;
+        }
+    }
+    segments.push(&selector[start..]);
+    Ok(segments.into_iter().filter(|s| !s.is_empty()).collect())
+}
+
+/// The length of a filter's contents, up to the `]` that closes it. A `]`
+/// inside a JSON string is part of the contents.
+fn bracket_len(rest: &str) -> Result<usize, SelectorError> {
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ']' => return Ok(i),
+            '"' => loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => {
+                        chars.next();
+                    }
+                    Some(_) => {}
+                    None => return Err(SelectorError::new("unterminated string in filter")),
+                }
+            },
+            _ => {}
+        }
+    }
+    Err(SelectorError::new("'[' without a matching ']'"))
+}
+
+fn parse_step(segment: &str) -> Result<Step, SelectorError> {
+    let (name, mut rest) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+    let name = match name {
+        "" => return Err(SelectorError::new("a filter must follow a key, '*' or '**'")),
+        "*" => Name::Children,
+        "**" => Name::Descendants,
+        key => Name::Key(
+            unescape_key(key)
+                .ok_or_else(|| SelectorError::new(format!("invalid escape in '{}'", key)))?,
+        ),
+    };
+    let mut filters = Vec::new();
+    while !rest.is_empty() {
+        let Some(inner) = rest.strip_prefix('[') else {
+            return Err(SelectorError::new(format!("unexpected '{}' after a filter", rest)));
+        };
+        let len = bracket_len(inner)?;
+        filters.push(parse_filter(&inner[..len])?);
+        rest = &inner[len + 1..];
+    }
+    Ok(Step { name, filters })
+}
+
+fn parse_filter(filter: &str) -> Result<Filter, SelectorError> {
+    let index = |text: &str| -> Result<Option<isize>, SelectorError> {
+        if text.is_empty() {
+            return Ok(None);
+        }
+        text.parse()
+            .map(Some)
+            .map_err(|_| SelectorError::new(format!("'{}' is not an index", text)))
+    };
+    let field = |text: &str| match path_keys(text) {
+        Some(keys) if !keys.is_empty() => Ok(keys),
+        Some(_) => Err(SelectorError::new("a filter needs a field or an index")),
+        None => Err(SelectorError::new(format!("invalid escape in '{}'", text))),
+    };
+    if let Ok(i) = filter.parse() {
+        return Ok(Filter::Index(i));
+    }
+    if let Some((start, end)) = filter.split_once(':')
+        && start.chars().chain(end.chars()).all(|c| c == '-' || c.is_ascii_digit())
+    {
+        return Ok(Filter::Slice(index(start)?, index(end)?));
+    }
+    match filter.split_once('=') {
+        Some((path, text)) => Ok(Filter::Equals {
+            path: field(path)?,
+            text: text.to_string(),
+            json: serde_json::from_str(text).ok(),
+        }),
+        None => Ok(Filter::Has(field(filter)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stocks() -> Node {
+        Node::from_value(json!({
+            "market": {
+                "acrn": {
+                    "ticker": "ACRN",
+                    "price": 12.5,
+                    "buy": {"#": {"href": "buy"}, "quantity": 0}
+                },
+                "ibm": {
+                    "ticker": "IBM",
+                    "price": "201.32",
+                    "buy": {"#": {"href": "buy"}, "quantity": 1}
+                }
+            },
+            "items": ["a", "b", "c", "d"]
+        }))
+        .unwrap()
+    }
+
+    fn select(selector: &str) -> Vec<(String, Value)> {
+        let root = stocks();
+        let selector = Selector::parse(selector).unwrap();
+        let matches = selector.select(&root);
+        matches.into_iter().map(|(path, node)| (path, node.to_json())).collect()
+    }
+
+    fn paths(selector: &str) -> Vec<String> {
+        select(selector).into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn wildcards_match_every_child() {
+        assert_eq!(
+            select("market/*/price"),
+            [
+                ("market/acrn/price".to_string(), json!(12.5)),
+                ("market/ibm/price".to_string(), json!("201.32"))
+            ]
+        );
+        assert_eq!(paths("items/*"), ["items/0", "items/1", "items/2", "items/3"]);
+    }
+
+    #[test]
+    fn double_wildcards_match_at_any_depth() {
+        assert_eq!(paths("**/quantity"), ["market/acrn/buy/quantity", "market/ibm/buy/quantity"]);
+        assert_eq!(paths("market/**/ticker"), ["market/acrn/ticker", "market/ibm/ticker"]);
+        assert_eq!(paths("**/**/quantity").len(), 2);
+        assert_eq!(paths("**")[..2], ["", "market"]);
+    }
+
+    #[test]
+    fn indexes_and_slices_pick_list_items() {
+        assert_eq!(paths("items[1]"), ["items/1"]);
+        assert_eq!(paths("items[-1]"), ["items/3"]);
+        assert_eq!(paths("items[1:3]"), ["items/1", "items/2"]);
+        assert_eq!(paths("items[:2]"), ["items/0", "items/1"]);
+        assert_eq!(paths("items[-2:]"), ["items/2", "items/3"]);
+        assert_eq!(paths("items[3:1]"), Vec::<String>::new());
+        assert_eq!(paths("items[0:10]").len(), 4);
+        assert_eq!(paths("market[0]"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn predicates_keep_matching_nodes() {
+        assert_eq!(paths("market/*[ticker=ACRN]"), ["market/acrn"]);
+        assert_eq!(paths(r#"market/*[ticker="IBM"]"#), ["market/ibm"]);
+        assert_eq!(paths("market/*[price=12.5]"), ["market/acrn"]);
+        assert_eq!(paths("market/*[price=201.32]"), ["market/ibm"]);
+        assert_eq!(paths("market/*[buy/quantity=1]/price"), ["market/ibm/price"]);
+        assert_eq!(paths("**[quantity]"), ["market/acrn/buy", "market/ibm/buy"]);
+        assert_eq!(paths("market/*[ticker=X]"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn brackets_and_quotes_may_hold_slashes() {
+        let root = Node::from_value(json!({"a": [{"url": "http://x/]"}]})).unwrap();
+        let selector = Selector::parse(r#"a/*[url="http://x/]"]/url"#).unwrap();
+        let matches = selector.select(&root);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].0, "a/0/url");
+    }
+
+    #[test]
+    fn invalid_selectors_are_rejected() {
+        for selector in ["items[0", "[0]", "items[0]x", "items[]", "items[a~2]", r#"a[b=""#] {
+            assert!(Selector::parse(selector).is_err(), "{}", selector);
+        }
+    }
+
+    #[test]
+    fn pointers_and_plain_paths_are_not_selectors() {
+        assert!(is_selector("market/*/price"));
+        assert!(is_selector("items[0:2]"));
+        assert!(!is_selector("market/acrn/price"));
+        assert!(!is_selector("/market/*"));
+    }
+}
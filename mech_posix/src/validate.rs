@@ -0,0 +1,236 @@
+// HyperMap resource validation
+//
+// Checks a resource's JSON against the format rules in the spec, collecting
+// every violation instead of stopping at the first, so a single run of
+// `mech validate` shows everything a server needs to fix.
+
+use std::fmt;
+
+use serde_json::Value;
+use url::Url;
+
+use crate::hypermap::escape_key;
+
+/// One way in which a resource breaks the spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Where the problem is, as a JSON Pointer from the top of the resource
+    /// (`/` itself for the top level). Attribute members appear under `#`,
+    /// e.g. `/market/acrn/buy/#/method`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Check `resource` against the spec, returning every violation found.
+pub fn validate(resource: &Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if resource.is_object() {
+        check_node(resource, "", &mut violations);
+    } else {
+        violations.push(Violation {
+            path: "/".to_string(),
+            message: format!("top level must be an object, not {}", type_name(resource)),
+        });
+    }
+    violations
+}
+
+fn check_node(value: &Value, path: &str, violations: &mut Vec<Violation>) {
+    match value {
+        Value::Object(members) => {
+            for (key, child) in members {
+                let child_path = format!("{}/{}", path, escape_key(key));
+                if key == "#" {
+                    check_attributes(child, &child_path, violations);
+                } else {
+                    check_node(child, &child_path, violations);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                check_node(item, &format!("{}/{}", path, i), violations);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_attributes(value: &Value, path: &str, violations: &mut Vec<Violation>) {
+    let mut violation = |path: String, message: String| {
+        violations.push(Violation { path, message });
+    };
+    let Value::Object(members) = value else {
+        violation(
+            path.to_string(),
+            format!("'#' must be an object, not {}", type_name(value)),
+        );
+        return;
+    };
+
+    if let Some(href) = members.get("href") {
+        let href_path = format!("{}/href", path);
+        match href {
+            Value::String(href) => {
+                if let Err(reason) = check_url(href) {
+                    violation(href_path, format!("href is not a valid URL: {}", reason));
+                }
+            }
+            _ => violation(href_path, format!("href must be a string, not {}", type_name(href))),
+        }
+    }
+
+    if let Some(method) = members.get("method") {
+        let method_path = format!("{}/method", path);
+        match method {
+            Value::String(method) if !is_http_token(method) => {
+                violation(method_path, format!("'{}' is not a valid HTTP method", method));
+            }
+            Value::String(method) if method.to_uppercase() != *method => violation(
+                method_path,
+                format!("method must be uppercase ('{}')", method.to_uppercase()),
+            ),
+            Value::String(_) => {}
+            _ => violation(
+                method_path,
+                format!("method must be a string, not {}", type_name(method)),
+            ),
+        }
+    }
+
+    if let Some(scripts) = members.get("scripts") {
+        let scripts_path = format!("{}/scripts", path);
+        let Value::Array(scripts) = scripts else {
+            violation(
+                scripts_path,
+                format!("scripts must be an array, not {}", type_name(scripts)),
+            );
+            return;
+        };
+        for (i, script) in scripts.iter().enumerate() {
+            let script_path = format!("{}/{}", scripts_path, i);
+            match script {
+                Value::String(script) => {
+                    if let Err(reason) = check_url(script) {
+                        violation(script_path, format!("script is not a valid URL: {}", reason));
+                    }
+                }
+                _ => violation(
+                    script_path,
+                    format!("script must be a string, not {}", type_name(script)),
+                ),
+            }
+        }
+    }
+}
+
+/// Check that `url` parses, either on its own or relative to a base URL.
+fn check_url(url: &str) -> Result<(), String> {
+    let base = Url::parse("https://base.invalid/").expect("base URL is valid");
+    if url.trim() != url {
+        return Err("leading or trailing whitespace".to_string());
+    }
+    base.join(url).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Whether `method` is an HTTP token (RFC 9110, section 5.6.2).
+fn is_http_token(method: &str) -> bool {
+    !method.is_empty()
+        && method
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn messages(resource: Value) -> Vec<String> {
+        validate(&resource).iter().map(Violation::to_string).collect()
+    }
+
+    #[test]
+    fn valid_resource_has_no_violations() {
+        let resource = json!({
+            "#": {"href": "/stocks/", "scripts": ["/js/stocks.js"]},
+            "market": {
+                "acrn": {
+                    "buy": {"#": {"href": "buy", "method": "POST"}, "quantity": 0}
+                }
+            },
+            "todos": [{"#": {"href": "https://example.com/todos/1"}}],
+            "custom": {"#": {"href": "?page=2", "method": "PROPFIND"}}
+        });
+        assert_eq!(messages(resource), Vec::<String>::new());
+    }
+
+    #[test]
+    fn top_level_must_be_an_object() {
+        assert_eq!(
+            messages(json!([{"title": "Learn HyperMap"}])),
+            ["/: top level must be an object, not an array"]
+        );
+    }
+
+    #[test]
+    fn attributes_must_be_an_object() {
+        assert_eq!(
+            messages(json!({"nav": {"#": "/home"}})),
+            ["/nav/#: '#' must be an object, not a string"]
+        );
+    }
+
+    #[test]
+    fn reports_every_violation_with_its_path() {
+        let resource = json!({
+            "a": {"#": {"href": 5, "method": "post"}},
+            "b": [{"#": {"href": "http://[::1", "method": "GET IT"}}],
+            "c": {"#": {"scripts": ["/ok.js", 7, " /x.js"]}},
+            "d": {"#": {"scripts": "/main.js"}}
+        });
+        assert_eq!(
+            messages(resource),
+            [
+                "/a/#/href: href must be a string, not a number",
+                "/a/#/method: method must be uppercase ('POST')",
+                "/b/0/#/href: href is not a valid URL: invalid IPv6 address",
+                "/b/0/#/method: 'GET IT' is not a valid HTTP method",
+                "/c/#/scripts/1: script must be a string, not a number",
+                "/c/#/scripts/2: script is not a valid URL: leading or trailing whitespace",
+                "/d/#/scripts: scripts must be an array, not a string",
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_escape_keys() {
+        assert_eq!(
+            messages(json!({"links": {"https://x.org/a~b": {"#": {"href": 1}}}})),
+            ["/links/https:~1~1x.org~1a~0b/#/href: href must be a string, not a number"]
+        );
+    }
+
+    #[test]
+    fn hash_is_only_special_on_objects() {
+        // A "#" value nested in data is only checked where it is a key
+        assert_eq!(messages(json!({"tags": ["#", {"#": {}}]})), Vec::<String>::new());
+    }
+}
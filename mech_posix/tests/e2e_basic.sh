@@ -46,7 +46,6 @@ echo "PASS: Daemon started"
 
 echo "=== Test: Open tab ==="
 "$MECH_BIN" open https://hypermap-example.deno.dev/
-sleep 3  # Give webview time to load
 
 echo "=== Test: Show tab ==="
 OUTPUT=$("$MECH_BIN" show 1)
@@ -87,7 +86,6 @@ fi
 
 echo "=== Test: Use control (navigate to stocks) ==="
 "$MECH_BIN" use 1:stocks
-sleep 2  # Wait for navigation
 
 OUTPUT=$("$MECH_BIN" show 1)
 echo "$OUTPUT"
@@ -99,9 +97,68 @@ else
     exit 1
 fi
 
+echo "=== Test: Back and forward ==="
+"$MECH_BIN" back 1
+if "$MECH_BIN" show 1 | grep -q "market"; then
+    echo "FAIL: Back stayed on the stocks page"
+    exit 1
+fi
+"$MECH_BIN" forward 1
+if "$MECH_BIN" show 1 | grep -q "market"; then
+    echo "PASS: Back and forward moved through history"
+else
+    echo "FAIL: Forward did not return to the stocks page"
+    exit 1
+fi
+if "$MECH_BIN" history 1 | grep -q '^\* 2'; then
+    echo "PASS: History marks the current page"
+else
+    echo "FAIL: History does not mark page 2 as current"
+    exit 1
+fi
+
+echo "=== Test: Goto a relative URL ==="
+"$MECH_BIN" goto 1 /
+"$MECH_BIN" goto 1 stocks/
+if "$MECH_BIN" show 1 | grep -q "market"; then
+    echo "PASS: Goto loaded the stocks page into tab 1"
+else
+    echo "FAIL: Goto did not load the stocks page"
+    exit 1
+fi
+
+echo "=== Test: Wait for a live price to change ==="
+if "$MECH_BIN" wait 1:market/acrn/price --changes --timeout 10; then
+    echo "PASS: Wait saw the price change"
+else
+    echo "FAIL: Wait did not see the price change"
+    exit 1
+fi
+
+echo "=== Test: Watch streams updates ==="
+# Two lines means the initial value plus at least one live change
+WATCH_OUTPUT=$(timeout 10 "$MECH_BIN" watch 1:market/acrn/price -o json | head -n 2)
+echo "$WATCH_OUTPUT"
+if [ "$(echo "$WATCH_OUTPUT" | wc -l)" -eq 2 ]; then
+    echo "PASS: Watch streamed an update"
+else
+    echo "FAIL: Watch did not stream an update"
+    exit 1
+fi
+
+echo "=== Test: Wait times out ==="
+STATUS=0
+"$MECH_BIN" wait 1:market/acrn/price --equals never --timeout 0.5 || STATUS=$?
+if [ "$STATUS" -eq 2 ]; then
+    echo "PASS: Wait exited 2 on timeout"
+else
+    echo "FAIL: Wait exited $STATUS, expected 2"
+    exit 1
+fi
+
 echo "=== Test: Fork tab ==="
+"$MECH_BIN" set 1:market/acrn/buy/quantity 7
 "$MECH_BIN" fork 1 --name stocks-copy
-sleep 2  # Wait for fork to load
 
 # Verify the forked tab exists and has the same content
 OUTPUT=$("$MECH_BIN" show stocks-copy)
@@ -113,6 +170,16 @@ else
     exit 1
 fi
 
+echo "=== Test: Fork keeps set values ==="
+OUTPUT=$("$MECH_BIN" show stocks-copy:market/acrn/buy/quantity --output json)
+if [ "$OUTPUT" = '"7"' ]; then
+    echo "PASS: Fork carried over the set value"
+else
+    echo "FAIL: Fork lost the set value"
+    echo "Got: $OUTPUT"
+    exit 1
+fi
+
 echo "=== Test: Tabs shows forked tab ==="
 TABS_OUTPUT=$("$MECH_BIN" tabs)
 echo "$TABS_OUTPUT"
@@ -127,10 +194,31 @@ echo "=== Test: Close forked tab ==="
 "$MECH_BIN" close stocks-copy
 echo "PASS: Closed forked tab"
 
+echo "=== Test: Tab IDs are not reused ==="
+"$MECH_BIN" fork 1 --name ids-check
+if "$MECH_BIN" show 2 > /dev/null 2>&1; then
+    echo "FAIL: Closed tab's ID was handed out again"
+    exit 1
+fi
+OUTPUT=$("$MECH_BIN" show 3:market/acrn/ticker)
+if [ -n "$OUTPUT" ]; then
+    echo "PASS: New tab got a fresh ID after closing tab 2"
+else
+    echo "FAIL: New tab not reachable by ID 3"
+    exit 1
+fi
+"$MECH_BIN" close ids-check
+
 echo "=== Test: Set value ==="
-# Set a value (we can't easily verify the DOM changed, but verify no error)
-"$MECH_BIN" set 1:market/acrn/submitOrder/quantity 10
-echo "PASS: Set command executed without error"
+"$MECH_BIN" set 1:market/acrn/buy/quantity 10
+OUTPUT=$("$MECH_BIN" show 1:market/acrn/buy/quantity --output json)
+if [ "$OUTPUT" = '"10"' ]; then
+    echo "PASS: Set changed the value"
+else
+    echo "FAIL: Set did not change the value"
+    echo "Got: $OUTPUT"
+    exit 1
+fi
 
 echo "=== Test: Close tab ==="
 "$MECH_BIN" close 1